@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    btreemap,
+    parser::Parser,
+    prompt_template::PromptTemplate,
+    schema::{usage::Usage, Generation, Message},
+};
+
+use super::{Chain, GenerateOptions, LlmError, Memory, LLM};
+
+/// CandidateSelector picks the best of the candidate answers to a task
+#[async_trait::async_trait]
+pub trait CandidateSelector: Serialize + Send + Sync {
+    /// select returns the index of the best candidate and the usage of selecting it
+    async fn select(
+        &self,
+        llm: &impl LLM,
+        task: &Message,
+        candidates: &[Message],
+        options: GenerateOptions,
+    ) -> Result<(usize, Option<Usage>), LlmError>;
+}
+
+/// MajorityVote picks the most common answer (self-consistency), ties go to the earlier candidate.
+/// with a parser the candidates vote with the parsed fields, e.g. the final answer of a reasoning,
+/// candidates that do not parse do not vote
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MajorityVote {
+    pub parser: Option<Parser>,
+}
+
+#[async_trait::async_trait]
+impl CandidateSelector for MajorityVote {
+    async fn select(
+        &self,
+        _llm: &impl LLM,
+        _task: &Message,
+        candidates: &[Message],
+        _options: GenerateOptions,
+    ) -> Result<(usize, Option<Usage>), LlmError> {
+        let votes = candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(i, candidate)| {
+                let text = candidate.content.to_string();
+                let vote = match &self.parser {
+                    Some(parser) => parser.parse(&text)?.join("\n"),
+                    None => text.split_whitespace().join(" "),
+                };
+                Some((vote, i))
+            })
+            .into_group_map();
+        votes
+            .values()
+            .max_by_key(|voters| (voters.len(), std::cmp::Reverse(voters[0])))
+            .map(|voters| (voters[0], None))
+            .ok_or_else(|| LlmError::MalformedResponse("MajorityVote: no candidate could be parsed".to_string()))
+    }
+}
+
+/// LLMJudge asks the llm which candidate answers the task best
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LLMJudge {
+    /// takes `{task}` and the numbered `{candidates}`
+    pub prompt_template: Option<PromptTemplate>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Verdict {
+    best: usize,
+}
+
+#[async_trait::async_trait]
+impl CandidateSelector for LLMJudge {
+    async fn select(
+        &self,
+        llm: &impl LLM,
+        task: &Message,
+        candidates: &[Message],
+        options: GenerateOptions,
+    ) -> Result<(usize, Option<Usage>), LlmError> {
+        let template = self.prompt_template.clone().unwrap_or_else(|| {
+            PromptTemplate::from(
+                "which of the candidate answers to the task is the best?
+Task: {task}
+
+Candidates:
+{candidates}"
+                    .to_string(),
+            )
+        });
+        let candidates_text = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, candidate)| format!("[{}] {}", i + 1, candidate.content))
+            .join("\n\n");
+        let prompt = template
+            .format(&btreemap! {
+                "task".to_string() => task.content.to_string(),
+                "candidates".to_string() => candidates_text,
+            })
+            .ok_or_else(|| LlmError::InvalidRequest("LLMJudge: cannot format the prompt".to_string()))?;
+        let judge_options = GenerateOptions {
+            n: None,
+            ..options
+        };
+        let (verdict, generation) = llm
+            .generate_structured::<Verdict>(
+                vec![Message {
+                    content: prompt.into(),
+                    ..task.clone()
+                }],
+                r#"{"best": the number of the best candidate}"#,
+                judge_options,
+            )
+            .await?;
+        if verdict.best == 0 || verdict.best > candidates.len() {
+            return Err(LlmError::MalformedResponse(format!(
+                "LLMJudge: there is no candidate {}",
+                verdict.best
+            )));
+        }
+        Ok((verdict.best - 1, generation.usage))
+    }
+}
+
+/// ParserScore picks the candidate whose parsed fields score highest,
+/// candidates that do not parse are skipped
+#[derive(Serialize)]
+pub struct ParserScore<F: Fn(&[String]) -> f64 + Send + Sync> {
+    pub parser: Parser,
+    #[serde(skip)]
+    pub score: F,
+}
+
+impl<F: Fn(&[String]) -> f64 + Send + Sync> ParserScore<F> {
+    pub fn new(parser: Parser, score: F) -> Self {
+        Self { parser, score }
+    }
+}
+
+#[async_trait::async_trait]
+impl<F: Fn(&[String]) -> f64 + Send + Sync> CandidateSelector for ParserScore<F> {
+    async fn select(
+        &self,
+        _llm: &impl LLM,
+        _task: &Message,
+        candidates: &[Message],
+        _options: GenerateOptions,
+    ) -> Result<(usize, Option<Usage>), LlmError> {
+        candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(i, candidate)| {
+                let fields = self.parser.parse(&candidate.content.to_string())?;
+                Some((i, (self.score)(&fields)))
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(i, _)| (i, None))
+            .ok_or_else(|| LlmError::MalformedResponse("ParserScore: no candidate could be parsed".to_string()))
+    }
+}
+
+/// CandidateChain asks the wrapped chain for `n` candidate answers and keeps the one the selector picks.
+/// candidates are requested with `GenerateOptions.n`, providers answering with fewer are asked again.
+/// `info.candidates` holds all candidates, the picked one is `info.selected`.
+/// it loads from config with MajorityVote or LLMJudge, ParserScore is built in code only
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "chain_type")]
+pub struct CandidateChain<C: Chain, S: CandidateSelector> {
+    pub chain: C,
+    pub n: u8,
+    pub selector: S,
+}
+
+impl<C: Chain, S: CandidateSelector> CandidateChain<C, S> {
+    pub fn new(chain: C, n: u8, selector: S) -> Self {
+        Self { chain, n, selector }
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: Chain + Send + Sync, S: CandidateSelector> Chain for CandidateChain<C, S> {
+    fn get_input_keys(&self) -> Vec<String> {
+        self.chain.get_input_keys()
+    }
+
+    fn get_output_keys(&self) -> Vec<String> {
+        self.chain.get_output_keys()
+    }
+
+    fn get_prompt_template(&self) -> PromptTemplate {
+        self.chain.get_prompt_template()
+    }
+
+    fn prepare_prompt(&self, input: &BTreeMap<String, String>) -> Option<Message> {
+        self.chain.prepare_prompt(input)
+    }
+
+    fn create_output(&self, generation: Generation) -> Option<BTreeMap<String, Message>> {
+        self.chain.create_output(generation)
+    }
+
+    async fn generate(
+        &self,
+        memory: Option<&Box<dyn Memory + Send + Sync>>,
+        llm: &impl LLM,
+        input: &BTreeMap<String, String>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Option<Generation>, LlmError> {
+        let n = self.n.max(1) as usize;
+        let Some(task) = self.prepare_prompt(input) else {
+            return Ok(None);
+        };
+        let first_options = GenerateOptions {
+            n: Some(n as u8),
+            ..options.clone()
+        };
+        let Some(first) = self.chain.generate(memory, llm, input, stop.clone(), first_options).await? else {
+            return Ok(None);
+        };
+        // logprobs are kept aligned with the candidates, empty for answers without them
+        let mut logprobs = first.logprobs;
+        logprobs.resize(first.text.len(), vec![]);
+        let mut candidates = first.text;
+        let mut usage = vec![first.usage];
+
+        // providers without multiple choices answer once, the missing candidates are asked one by one
+        let single_options = GenerateOptions {
+            n: None,
+            ..options.clone()
+        };
+        let missing = (candidates.len()..n).map(|_| {
+            self.chain
+                .generate(memory, llm, input, stop.clone(), single_options.clone())
+        });
+        for mut generation in futures::future::try_join_all(missing).await?.into_iter().flatten() {
+            generation.logprobs.resize(generation.text.len(), vec![]);
+            logprobs.extend(generation.logprobs);
+            candidates.extend(generation.text);
+            usage.push(generation.usage);
+        }
+        candidates.truncate(n);
+        if candidates.is_empty() {
+            return Err(LlmError::MalformedResponse("CandidateChain: no candidate".to_string()));
+        }
+
+        let (selected, selection_usage) = self
+            .selector
+            .select(llm, &task, &candidates, single_options)
+            .await?;
+        let selected = selected.min(candidates.len() - 1);
+        usage.push(selection_usage);
+        let mut generation = Generation {
+            text: vec![candidates[selected].clone()],
+            usage: Usage::aggregate(usage),
+            info: None,
+            logprobs: match &logprobs[selected] {
+                tokens if tokens.is_empty() => vec![],
+                tokens => vec![tokens.clone()],
+            },
+        };
+        generation.set_info(
+            "candidates",
+            candidates.iter().map(|c| c.content.to_string()).collect_vec(),
+        );
+        generation.set_info("selected", selected);
+        Ok(Some(generation))
+    }
+}
+
+#[tokio::test]
+async fn test_candidate_chain() {
+    use crate::chain::llm_chain::LLMChain;
+    use crate::llm::fake::FakeLLM;
+
+    let input = btreemap! {
+        "question".to_string() => "what is 6 * 7?".to_string()
+    };
+    let chain = || LLMChain::new(Some(PromptTemplate::from("{question}".to_string())));
+    let parser: Parser = serde_json::from_str(r#"{"regex": "answer: (\\d+)", "taking_index": [1]}"#).unwrap();
+
+    // the fake answers once per call, so the chain asks three times
+    let llm = FakeLLM::new([
+        "6 * 7 = 42, answer: 42",
+        "6 * 7 is 36, answer: 36",
+        "it is 42, answer: 42",
+    ]);
+    let voting = CandidateChain::new(chain(), 3, MajorityVote { parser: Some(parser.clone()) });
+    let res = voting.generate(None, &llm, &input, vec![], Default::default()).await.unwrap().unwrap();
+    assert_eq!(res.text[0].content, "6 * 7 = 42, answer: 42");
+    // a candidate step loads from config like the rest of a pipeline
+    let loaded: CandidateChain<LLMChain, MajorityVote> =
+        serde_json::from_str(&serde_json::to_string(&voting).unwrap()).unwrap();
+    assert_eq!(loaded.n, 3);
+    assert!(loaded.selector.parser.is_some());
+    assert_eq!(res.info.unwrap()["candidates"].as_array().unwrap().len(), 3);
+    assert_eq!(llm.calls()[0].options.n, Some(3));
+
+    let llm = FakeLLM::new(["answer: 1", "answer: 2"]).on("^which of the candidate", r#"{"best": 2}"#);
+    let judged = CandidateChain::new(chain(), 2, LLMJudge::default());
+    let res = judged.generate(None, &llm, &input, vec![], Default::default()).await.unwrap().unwrap();
+    assert_eq!(res.text[0].content, "answer: 2");
+    assert!(llm.calls()[2].prompt().contains("[2] answer: 2"));
+
+    let llm = FakeLLM::new(["answer: 7", "no idea", "answer: 9"]);
+    let scored = CandidateChain::new(
+        chain(),
+        3,
+        ParserScore::new(parser, |fields| fields[0].parse().unwrap_or(0.0)),
+    );
+    let res = scored.apply(None, &llm, &input, vec![], Default::default()).await.unwrap().unwrap();
+    assert_eq!(res["answer"].content, "answer: 9");
+}
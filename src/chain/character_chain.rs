@@ -10,7 +10,7 @@ use crate::{
     schema::{Generation, Message},
 };
 
-use super::{Chain, Memory, LLM};
+use super::{Chain, GenerateOptions, LlmError, Memory, LLM};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Character {
@@ -63,7 +63,7 @@ the person you speek to has following information
 
     fn create_output(&self, generation: Generation) -> Option<BTreeMap<String, Message>> {
         let mut output = BTreeMap::new();
-        output.insert("answer".to_string(), generation.text[0].clone());
+        output.insert("answer".to_string(), generation.text.into_iter().next()?);
         Some(output)
     }
 
@@ -75,16 +75,19 @@ the person you speek to has following information
         llm: &impl LLM,
         input: &BTreeMap<String, String>,
         stop: Vec<String>,
-    ) -> Option<Generation> {
-        let prompt = self.prepare_prompt(input);
+        options: GenerateOptions,
+    ) -> Result<Option<Generation>, LlmError> {
+        let Some(prompt) = self.prepare_prompt(input) else {
+            return Ok(None);
+        };
         if let Some(mem) = memory {
-            let mut his = mem.get_history().await.unwrap();
-            his.push(prompt?);
-            Some(llm.generate(his, stop).await)
+            let mut his = mem.get_history().await.map_err(|e| LlmError::Memory(e.to_string()))?;
+            his.push(prompt);
+            Ok(Some(llm.generate(his, stop, options).await?))
         } else {
             let mut his = Vec::new();
-            his.push(prompt?);
-            Some(llm.generate(his, stop).await)
+            his.push(prompt);
+            Ok(Some(llm.generate(his, stop, options).await?))
         }
     }
 }
@@ -93,11 +96,12 @@ the person you speek to has following information
 async fn test_character() {
     use crate::schema::memory::InMemMemory;
     use crate::llm::client::openai::OpenAIClient;
+    use crate::schema::Role;
     use crate::btreemap;
     dotenvy::dotenv().unwrap();
-    let mem = Box::new(InMemMemory::from(vec![Message{role:"user".to_string(),content:"你打的真菜".to_string(),},Message{role:"assistant".to_string(),content:"上路被三人越塔，打野不在我怎么去，你告诉我？上路被三人越塔我都能保得住他吗？如果盲僧在的话我为什么不在？你告诉我，昂？盲僧都没有在为什么我要去......为...盲僧都不在你告诉我为什么我要去啊？啊？他被打野先越塔然后中单赶过去了，盲僧不在我为什么要去啊？啊？你...你告诉我，来，盲僧不在我为什么要去？你...来，我给你房管，你给我说话，来，这个叫你mud bee尊尼获加的这个臭.寄.吧.杠精你给我说话，来，你今天要说不明白你m明天就被车创死。你懂不懂？你m，我就看不惯你这种低分g在这抬杠呢。打野都没有反蹲到上路我怎么...我怎么保他？啊？c.n.m打野不在上路我怎么保他？不是急眼了你能说明白你就行，行不行？不...g东西你什么都说不明白你在这穷抬杠有什么意义吗？你告诉我？
+    let mem = Box::new(InMemMemory::from(vec![Message{role:Role::User,content:"你打的真菜".into(),..Default::default()},Message{role:Role::Assistant,content:"上路被三人越塔，打野不在我怎么去，你告诉我？上路被三人越塔我都能保得住他吗？如果盲僧在的话我为什么不在？你告诉我，昂？盲僧都没有在为什么我要去......为...盲僧都不在你告诉我为什么我要去啊？啊？他被打野先越塔然后中单赶过去了，盲僧不在我为什么要去啊？啊？你...你告诉我，来，盲僧不在我为什么要去？你...来，我给你房管，你给我说话，来，这个叫你mud bee尊尼获加的这个臭.寄.吧.杠精你给我说话，来，你今天要说不明白你m明天就被车创死。你懂不懂？你m，我就看不惯你这种低分g在这抬杠呢。打野都没有反蹲到上路我怎么...我怎么保他？啊？c.n.m打野不在上路我怎么保他？不是急眼了你能说明白你就行，行不行？不...g东西你什么都说不明白你在这穷抬杠有什么意义吗？你告诉我？
                 你白银觉得是我的锅，那就是我的锅，为什么你知道吗？因为白银说的话，就像是一个癌症晚期患者说的话一样。他都已经这样了，你为什么不顺从他呢?你总要给人最后一段时间一个好的回忆吧，最后的时光里。因为白银这个段位很尴尬，白银黄金再往上一点，白金钻石，可能说，欸，有点实力，能操作一下。白银往下，黄铜，一到五，啊，人家是纯属玩游戏的，因为太垃 圾了，自己也知道自己没什么实力。但白银，上不去下不来的这个段位，他觉得，黄铜的人不配跟他一起玩儿，对吧？黄铜是最垃 圾的。但是呢他想上去，他又上不去，所以这个分段是最尴尬的，没办法，卡在这里了。想操作，又操作不起来，掉下去吧，他又觉得不值得，对吧，我好不容易从黄铜打到...打到白银了，我为什么还要掉下去呢?这个人说优越g 越说越起劲，为什么他会这么说?因为他是白银呐。他觉得你比我段位高，你说的任何话都是优越，我并不管你说的有没有道理。我白银，我最猛，我S8我上我能夺冠，那打比赛全是s.b。你比我段位高你说话就是放屁，这就是这种人的想法。但是呢，他的想法是对的，为什么呢？因为他癌症晚期。没办法，我同意，对不起，我优越了。可能是我膨胀了，不好意思啊，我膨胀了。我白银是没操作，难道我就看不懂谁背锅吗？不是，如果你看得懂的话，就不会在这里抬杠了，对吧。
-                ".to_string(),},]));
+                ".into(),..Default::default()},]));
     let chain = CharacterChain {
         character: Character {
             user_info: "2B青年，喜欢玩英雄联盟".to_string(),
@@ -127,8 +131,67 @@ async fn test_character() {
                 然后被单杀".to_string()
             },
             vec!["stop".to_string()],
+            Default::default(),
         )
         .await;
 
     println!("{:?}", res);
 }
+
+#[tokio::test]
+async fn test_character_cassette() {
+    use crate::btreemap;
+    use crate::llm::cassette::CassetteLLM;
+    use crate::llm::client::openai::{OpenAIClient, OpenAIConfig};
+    use crate::schema::memory::InMemMemory;
+    use crate::schema::Role;
+
+    let mem: Box<dyn Memory + Send + Sync> = Box::new(InMemMemory::from(vec![
+        Message {
+            role: Role::User,
+            content: "you played terribly".into(),
+            ..Default::default()
+        },
+        Message {
+            role: Role::Assistant,
+            content: "three of them dived top and the jungler was nowhere, what was I supposed to do?".into(),
+            ..Default::default()
+        },
+    ]));
+    let chain = CharacterChain {
+        character: Character {
+            user_info: "a silver player who loves League of Legends".to_string(),
+            bot_info: "a hot-tempered streamer who never admits a mistake".to_string(),
+            bot_name: "Dianbang".to_string(),
+            user_name: "akarachan".to_string(),
+        },
+        prompt_template: None,
+    };
+    // replayed from the committed cassette, delete it and set OPENAI_API_KEY to record it again
+    let executor = CassetteLLM::new(
+        OpenAIClient {
+            temperature: Some(0.0),
+            config: OpenAIConfig {
+                api_base: "https://api.openai.com/v1".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        "test_utils/cassettes/character_chain_openai.json",
+    );
+
+    let res = chain
+        .apply(
+            Some(&mem),
+            &executor,
+            &btreemap! {
+                "question".to_string() => "you died three times before level 6, how will you ever go pro?".to_string()
+            },
+            vec!["stop".to_string()],
+            Default::default(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(res["answer"].content.to_string().contains("jungler"));
+}
@@ -4,7 +4,6 @@ use async_trait::async_trait;
 
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
-use zhipuai_sdk_rust::models::characterglm::CharacterGLMMeta;
 
 use crate::{
     prompt_template::PromptTemplate,
@@ -48,7 +47,7 @@ impl Chain for LLMChain {
 
     fn create_output(&self, generation: Generation) -> Option<BTreeMap<String, Message>> {
         let mut output = BTreeMap::new();
-        output.insert("answer".to_string(), generation.text[0].clone());
+        output.insert("answer".to_string(), generation.text.into_iter().next()?);
         Some(output)
     }
 }
@@ -73,6 +72,7 @@ async fn test_llm_chain_openai() {
                 "question".to_string() => "What is human?".to_string()
             },
             vec!["stop".to_string()],
+            Default::default(),
         )
         .await;
 
@@ -82,13 +82,14 @@ async fn test_llm_chain_openai() {
 #[tokio::test]
 async fn test_llm_chain_glm() {
     use crate::schema::memory::InMemMemory;
+    use crate::schema::Role;
     use crate::btreemap;
     use crate::llm::client::glm::*;
     use crate::schema::memory::Memory;
     dotenvy::dotenv().unwrap();
-    let mem = Box::new(InMemMemory::from(vec![Message{role:"user".to_string(),content:"你打的真菜".to_string(),},Message{role:"bot".to_string(),content:"上路被三人越塔，打野不在我怎么去，你告诉我？上路被三人越塔我都能保得住他吗？如果盲僧在的话我为什么不在？你告诉我，昂？盲僧都没有在为什么我要去......为...盲僧都不在你告诉我为什么我要去啊？啊？他被打野先越塔然后中单赶过去了，盲僧不在我为什么要去啊？啊？你...你告诉我，来，盲僧不在我为什么要去？你...来，我给你房管，你给我说话，来，这个叫你mud bee尊尼获加的这个臭.寄.吧.杠精你给我说话，来，你今天要说不明白你m明天就被车创死。你懂不懂？你m，我就看不惯你这种低分g在这抬杠呢。打野都没有反蹲到上路我怎么...我怎么保他？啊？c.n.m打野不在上路我怎么保他？不是急眼了你能说明白你就行，行不行？不...g东西你什么都说不明白你在这穷抬杠有什么意义吗？你告诉我？
+    let mem = Box::new(InMemMemory::from(vec![Message{role:Role::User,content:"你打的真菜".into(),..Default::default()},Message{role:Role::Assistant,content:"上路被三人越塔，打野不在我怎么去，你告诉我？上路被三人越塔我都能保得住他吗？如果盲僧在的话我为什么不在？你告诉我，昂？盲僧都没有在为什么我要去......为...盲僧都不在你告诉我为什么我要去啊？啊？他被打野先越塔然后中单赶过去了，盲僧不在我为什么要去啊？啊？你...你告诉我，来，盲僧不在我为什么要去？你...来，我给你房管，你给我说话，来，这个叫你mud bee尊尼获加的这个臭.寄.吧.杠精你给我说话，来，你今天要说不明白你m明天就被车创死。你懂不懂？你m，我就看不惯你这种低分g在这抬杠呢。打野都没有反蹲到上路我怎么...我怎么保他？啊？c.n.m打野不在上路我怎么保他？不是急眼了你能说明白你就行，行不行？不...g东西你什么都说不明白你在这穷抬杠有什么意义吗？你告诉我？
                 你白银觉得是我的锅，那就是我的锅，为什么你知道吗？因为白银说的话，就像是一个癌症晚期患者说的话一样。他都已经这样了，你为什么不顺从他呢?你总要给人最后一段时间一个好的回忆吧，最后的时光里。因为白银这个段位很尴尬，白银黄金再往上一点，白金钻石，可能说，欸，有点实力，能操作一下。白银往下，黄铜，一到五，啊，人家是纯属玩游戏的，因为太垃 圾了，自己也知道自己没什么实力。但白银，上不去下不来的这个段位，他觉得，黄铜的人不配跟他一起玩儿，对吧？黄铜是最垃 圾的。但是呢他想上去，他又上不去，所以这个分段是最尴尬的，没办法，卡在这里了。想操作，又操作不起来，掉下去吧，他又觉得不值得，对吧，我好不容易从黄铜打到...打到白银了，我为什么还要掉下去呢?这个人说优越g 越说越起劲，为什么他会这么说?因为他是白银呐。他觉得你比我段位高，你说的任何话都是优越，我并不管你说的有没有道理。我白银，我最猛，我S8我上我能夺冠，那打比赛全是s.b。你比我段位高你说话就是放屁，这就是这种人的想法。但是呢，他的想法是对的，为什么呢？因为他癌症晚期。没办法，我同意，对不起，我优越了。可能是我膨胀了，不好意思啊，我膨胀了。我白银是没操作，难道我就看不懂谁背锅吗？不是，如果你看得懂的话，就不会在这里抬杠了，对吧。
-                ".to_string(),},]));
+                ".into(),..Default::default()},]));
     let chain = LLMChain {
         prompt_template: Some(PromptTemplate::from("{question}".to_string())),
     };
@@ -117,8 +118,93 @@ async fn test_llm_chain_glm() {
                 然后被单杀".to_string()
             },
             vec!["stop".to_string()],
+            Default::default(),
         )
         .await;
 
     println!("{:?}", res);
 }
+
+#[tokio::test]
+async fn test_llm_chain_cassette() {
+    use crate::btreemap;
+    use crate::llm::cassette::CassetteLLM;
+    use crate::llm::client::openai::*;
+
+    // replayed from the committed cassette, delete it and set OPENAI_API_KEY to record it again.
+    // the api base is fixed so an OPENAI_API_BASE in the environment does not change the keys
+    let executor = CassetteLLM::new(
+        OpenAIClient {
+            temperature: Some(0.0),
+            config: OpenAIConfig {
+                api_base: "https://api.openai.com/v1".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        "test_utils/cassettes/llm_chain_openai.json",
+    );
+    let chain = LLMChain::new(Some(PromptTemplate::from("{question}".to_string())));
+
+    let res = chain
+        .apply(
+            None,
+            &executor,
+            &btreemap! {
+                "question".to_string() => "What is human?".to_string()
+            },
+            vec!["stop".to_string()],
+            Default::default(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(res["answer"].content.to_string().starts_with("A human is"));
+    // the recorded call was paid for once, its replay is free
+    assert_eq!(res.usage.unwrap().cost, Some(0.0));
+}
+
+#[tokio::test]
+async fn test_llm_chain_memory_error() {
+    use crate::btreemap;
+    use crate::llm::fake::FakeLLM;
+    use crate::llm::LlmError;
+    use crate::schema::memory::Memory;
+
+    struct BrokenMemory;
+
+    #[async_trait::async_trait]
+    impl Memory for BrokenMemory {
+        async fn push_front(&self, _message: Message) -> anyhow::Result<()> {
+            anyhow::bail!("store is down")
+        }
+        async fn push_back(&self, _message: Message) -> anyhow::Result<()> {
+            anyhow::bail!("store is down")
+        }
+        async fn pop_front(&self) -> anyhow::Result<Message> {
+            anyhow::bail!("store is down")
+        }
+        async fn pop_back(&self) -> anyhow::Result<Message> {
+            anyhow::bail!("store is down")
+        }
+        async fn get_history(&self) -> anyhow::Result<Vec<Message>> {
+            anyhow::bail!("store is down")
+        }
+    }
+
+    // a failing memory fails the call instead of panicking
+    let mem: Box<dyn Memory + Send + Sync> = Box::new(BrokenMemory);
+    let chain = LLMChain::new(Some(PromptTemplate::from("{question}".to_string())));
+    let res = chain
+        .apply(
+            Some(&mem),
+            &FakeLLM::new(["never asked"]),
+            &btreemap! {
+                "question".to_string() => "What is human?".to_string()
+            },
+            vec![],
+            Default::default(),
+        )
+        .await;
+    assert!(matches!(res, Err(LlmError::Memory(_))));
+}
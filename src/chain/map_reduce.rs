@@ -3,12 +3,12 @@ use std::collections::BTreeMap;
 use crate::{
     chain::Chain,
     prompt_template::PromptTemplate,
-    schema::{Generation, Message},
+    schema::{usage::Usage, Generation, Message},
 };
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
-use super::{Memory, LLM};
+use super::{GenerateOptions, LlmError, Memory, LLM};
 
 /// usage: {1: {q1}, 2: {q2}} -> {output}
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,7 +39,7 @@ impl<MapChain: Chain + Serialize + Send + Sync, ReduceChain: Chain + Serialize +
 
     fn create_output(&self, generation: Generation) -> Option<BTreeMap<String, Message>> {
         let mut output = BTreeMap::new();
-        output.insert("answer".to_string(), generation.text[0].clone());
+        output.insert("answer".to_string(), generation.text.into_iter().next()?);
         Some(output)
     }
 
@@ -49,7 +49,8 @@ impl<MapChain: Chain + Serialize + Send + Sync, ReduceChain: Chain + Serialize +
         llm: &impl LLM,
         input: &BTreeMap<String, String>,
         stop: Vec<String>,
-    ) -> Option<Generation> {
+        options: GenerateOptions,
+    ) -> Result<Option<Generation>, LlmError> {
         let mut inputs: Vec<BTreeMap<String, String>> = vec![];
         let mut input2 = input.clone();
         for (k, v) in input {
@@ -66,27 +67,43 @@ impl<MapChain: Chain + Serialize + Send + Sync, ReduceChain: Chain + Serialize +
 
         let futs = inputs.iter().map(|input| async {
             let mut his = Vec::new();
-            let prompt = self.map_chain.prepare_prompt(input).unwrap();
+            let prompt = self.map_chain.prepare_prompt(input).ok_or_else(|| {
+                LlmError::InvalidRequest("MapReduceChain: cannot prepare the map prompt from the input".to_string())
+            })?;
             his.push(prompt);
-            let output = llm.generate(his, stop.clone()).await;
-            (output.text[0].content.clone(), output.info)
+            let output = llm.generate(his, stop.clone(), options.clone()).await?;
+            let answer = output
+                .text
+                .first()
+                .ok_or_else(|| LlmError::MalformedResponse("no answer".to_string()))?;
+            Ok::<_, LlmError>((answer.content.to_string(), output.usage))
         });
 
-        let res = futures::future::join_all(futs).await;
+        // the first failure drops the calls still in flight
+        let res = futures::future::try_join_all(futs).await?;
         println!("{:#?}", res);
-        let mut prompt = self.reduce_chain.prepare_prompt(&input).unwrap();
+        let Some(mut prompt) = self.reduce_chain.prepare_prompt(&input) else {
+            return Ok(None);
+        };
         prompt.content = format!(
             "{}\n{}",
             prompt.content,
             res.iter().map(|i| &i.0).join("\n")
-        );
+        )
+        .into();
 
         let mut his = Vec::new();
         if let Some(mem) = memory {
-            his = mem.get_history().await.unwrap();
+            his = mem.get_history().await.map_err(|e| LlmError::Memory(e.to_string()))?;
         }
         his.push(prompt);
-        Some(llm.generate(his, stop).await)
+        let mut generation = llm.generate(his, stop, options).await?;
+        generation.usage = Usage::aggregate(
+            res.into_iter()
+                .map(|(_, usage)| usage)
+                .chain([generation.usage]),
+        );
+        Ok(Some(generation))
     }
 }
 
@@ -114,6 +131,119 @@ async fn test_map_reduce() {
         reduce_chain,
     };
 
-    let res = chain.generate(None, &executor, &inputs, vec![]).await;
+    let res = chain.generate(None, &executor, &inputs, vec![], Default::default()).await;
     println!("{:#?}", res);
 }
+
+#[tokio::test]
+async fn test_map_reduce_fake() {
+    use crate::btreemap;
+    use crate::chain::llm_chain::LLMChain;
+    use crate::llm::fake::FakeLLM;
+
+    let mut llm = FakeLLM::new(["an essay"])
+        .on("What is human", "a human is a person")
+        .on("What is computer", "a computer is a machine");
+    llm.usage = Some(Usage {
+        prompt_tokens: 10,
+        completion_tokens: 5,
+        cost: None,
+    });
+    let chain = MapReduceChain {
+        map_chain: LLMChain::new(Some(PromptTemplate::from("{question}".to_string()))),
+        reduce_chain: LLMChain::new(Some(PromptTemplate::from("{question}".to_string()))),
+    };
+    let inputs = btreemap! {
+        "question".to_string() => "write an essay about:".to_string(),
+        "1".to_string() => r#"{"question": "What is human?"}"#.to_string(),
+        "2".to_string() => r#"{"question": "What is computer?"}"#.to_string(),
+    };
+
+    let res = chain.generate(None, &llm, &inputs, vec![], Default::default()).await.unwrap().unwrap();
+    assert_eq!(res.text[0].content, "an essay");
+    // the usage of the map calls is added to the reduce call
+    assert_eq!(res.usage.unwrap().prompt_tokens, 30);
+    assert_eq!(res.usage.unwrap().completion_tokens, 15);
+
+    let calls = llm.calls();
+    assert_eq!(calls.len(), 3);
+    assert_eq!(
+        calls[2].prompt(),
+        "write an essay about:\na human is a person\na computer is a machine"
+    );
+    // apply reports the spend of the whole run next to the output
+    let mut llm = FakeLLM::new(["an essay"])
+        .on("What is human", "a human is a person")
+        .on("What is computer", "a computer is a machine");
+    llm.usage = Some(Usage {
+        prompt_tokens: 10,
+        completion_tokens: 5,
+        cost: Some(0.001),
+    });
+    let res = chain.apply(None, &llm, &inputs, vec![], Default::default()).await.unwrap().unwrap();
+    assert_eq!(res["answer"].content, "an essay");
+    let usage = res.usage.unwrap();
+    assert_eq!(usage.total_tokens(), 45);
+    assert!((usage.cost.unwrap() - 0.003).abs() < 1e-12);
+
+    // a map input without the prompt variables fails the call instead of panicking
+    let inputs = btreemap! {
+        "question".to_string() => "write an essay about:".to_string(),
+        "1".to_string() => r#"{"topic": "What is human?"}"#.to_string(),
+    };
+    let res = chain.generate(None, &llm, &inputs, vec![], Default::default()).await;
+    assert!(matches!(res, Err(LlmError::InvalidRequest(_))));
+}
+
+#[tokio::test]
+async fn test_map_reduce_cancel() {
+    use std::time::{Duration, Instant};
+
+    use crate::btreemap;
+    use crate::chain::{llm_chain::LLMChain, RunControl};
+    use crate::llm::fake::FakeLLM;
+
+    let mut llm = FakeLLM::new(["an essay"])
+        .on("What is human", "a human is a person")
+        .on("What is computer", "a computer is a machine");
+    llm.delay = Some(Duration::from_secs(5));
+    let chain = MapReduceChain {
+        map_chain: LLMChain::new(Some(PromptTemplate::from("{question}".to_string()))),
+        reduce_chain: LLMChain::new(Some(PromptTemplate::from("{question}".to_string()))),
+    };
+    let inputs = btreemap! {
+        "question".to_string() => "write an essay about:".to_string(),
+        "1".to_string() => r#"{"question": "What is human?"}"#.to_string(),
+        "2".to_string() => r#"{"question": "What is computer?"}"#.to_string(),
+    };
+
+    // cancelling drops both map calls in flight, the reduce call is never sent
+    let control = RunControl::default();
+    let cancel = control.cancel.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        cancel.cancel();
+    });
+    let started = Instant::now();
+    let res = chain
+        .apply_with_control(None, &llm, &inputs, vec![], Default::default(), &control)
+        .await;
+    assert!(matches!(res, Err(LlmError::Cancelled)));
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(llm.calls().len(), 2);
+
+    let control = RunControl::new(Duration::from_millis(50));
+    let res = chain
+        .apply_with_control(None, &llm, &inputs, vec![], Default::default(), &control)
+        .await;
+    assert!(matches!(res, Err(LlmError::Timeout(_))));
+
+    // a per-call timeout fails the map call
+    let options = GenerateOptions {
+        timeout: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    let res = chain.apply(None, &llm, &inputs, vec![], options).await;
+    assert!(matches!(res, Err(LlmError::Timeout(d)) if d == Duration::from_millis(50)));
+    assert_eq!(llm.calls()[5].options.timeout, Some(Duration::from_millis(50)));
+}
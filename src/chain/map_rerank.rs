@@ -4,12 +4,32 @@ use crate::{
     btreemap,
     chain::Chain,
     prompt_template::PromptTemplate,
-    schema::{Generation, Message},
+    schema::{usage::Usage, Generation, Message, Role},
 };
-use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
-use super::{Memory, LLM};
+use super::{GenerateOptions, LlmError, Memory, LLM};
+
+const RERANK_FORMAT: &str = r#"{"score": your score, "doc": copy the Doc above}"#;
+
+/// RerankScore is the answer of a rerank call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RerankScore {
+    score: f64,
+    doc: String,
+}
+
+/// RerankScoring is how the relevance of a doc is scored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RerankScoring {
+    /// the model writes a json score
+    #[default]
+    Json,
+    /// the model answers yes or no, the score is the probability of yes from the token logprobs.
+    /// needs a provider with logprobs, falls back to the answer text without them
+    Logprobs,
+}
 
 /// usage: {1: {q1}, 2: {q2}} -> {output}
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,6 +37,54 @@ use super::{Memory, LLM};
 pub struct MapRerankChain<MapChain: Chain + Serialize + Send + Sync> {
     prompt_template: Option<PromptTemplate>,
     map_chain: MapChain,
+    #[serde(default)]
+    scoring: RerankScoring,
+}
+
+impl<MapChain: Chain + Serialize + Send + Sync> MapRerankChain<MapChain> {
+    /// score_by_logprobs asks whether the doc answers the question and scores it with P(yes) / (P(yes) + P(no))
+    async fn score_by_logprobs(
+        &self,
+        llm: &impl LLM,
+        prompt: Message,
+        doc: &str,
+        options: GenerateOptions,
+    ) -> Result<(RerankScore, Option<Usage>), LlmError> {
+        let options = GenerateOptions {
+            n: None,
+            max_tokens: Some(1),
+            logprobs: Some(true),
+            top_logprobs: Some(5),
+            ..options
+        };
+        let output = llm.generate(vec![prompt], vec![], options).await?;
+        let score = match output.logprobs.first().and_then(|tokens| tokens.first()) {
+            Some(token) => {
+                let (yes, no) = (token.probability_of("yes"), token.probability_of("no"));
+                if yes + no > 0.0 {
+                    yes / (yes + no)
+                } else {
+                    0.0
+                }
+            }
+            None => {
+                println!("Warning: MapRerankChain: no logprobs returned, scoring by the answer");
+                let answer = output.text.first().map(|m| m.content.to_string()).unwrap_or_default();
+                if answer.trim().to_lowercase().starts_with("yes") {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+        };
+        Ok((
+            RerankScore {
+                score,
+                doc: doc.to_string(),
+            },
+            output.usage,
+        ))
+    }
 }
 
 #[async_trait::async_trait]
@@ -32,22 +100,25 @@ impl<MapChain: Chain + Serialize + Send + Sync> Chain for MapRerankChain<MapChai
     }
 
     fn get_prompt_template(&self) -> PromptTemplate {
-        self.prompt_template.clone().unwrap_or_else(|| {
-            PromptTemplate::from(
+        self.prompt_template.clone().unwrap_or_else(|| match self.scoring {
+            RerankScoring::Json => PromptTemplate::from(
                 "score the relativeness of the document to the answer from 0.0 to 1.0
 Question: {question}
-Doc: {answer}
-
-output format: \\{\"score\": your score goes here, \"doc\": copy the Doc above \\}
-for example: \\{\"score\": 0.5, \"doc\": \"blablabla\"\\}"
+Doc: {answer}"
+                    .to_string(),
+            ),
+            RerankScoring::Logprobs => PromptTemplate::from(
+                "does the document answer the question? answer yes or no
+Question: {question}
+Doc: {answer}"
                     .to_string(),
-            )
+            ),
         })
     }
 
     fn create_output(&self, generation: Generation) -> Option<BTreeMap<String, Message>> {
         let mut output = BTreeMap::new();
-        output.insert("answer".to_string(), generation.text[0].clone());
+        output.insert("answer".to_string(), generation.text.into_iter().next()?);
         Some(output)
     }
 
@@ -57,7 +128,8 @@ for example: \\{\"score\": 0.5, \"doc\": \"blablabla\"\\}"
         llm: &impl LLM,
         input: &BTreeMap<String, String>,
         stop: Vec<String>,
-    ) -> Option<Generation> {
+        options: GenerateOptions,
+    ) -> Result<Option<Generation>, LlmError> {
         let mut inputs: Vec<BTreeMap<String, String>> = vec![];
         let mut input2 = input.clone();
         for (k, v) in input {
@@ -71,59 +143,65 @@ for example: \\{\"score\": 0.5, \"doc\": \"blablabla\"\\}"
             }
         }
         let input = input2;
+        let Some(question) = input.get("question") else {
+            return Ok(None);
+        };
 
         let futs = inputs.iter().map(|input| async {
             let mut his = Vec::new();
-            let prompt = self.map_chain.prepare_prompt(input).unwrap();
+            let prompt = self.map_chain.prepare_prompt(input).ok_or_else(|| {
+                LlmError::InvalidRequest("MapRerankChain: cannot prepare the map prompt from the input".to_string())
+            })?;
             his.push(prompt);
-            let output = llm.generate(his, stop.clone()).await;
-            (output.text[0].content.clone(), output.info)
+            let output = llm.generate(his, stop.clone(), options.clone()).await?;
+            let answer = output
+                .text
+                .first()
+                .ok_or_else(|| LlmError::MalformedResponse("no answer".to_string()))?;
+            Ok::<_, LlmError>((answer.content.to_string(), output.usage))
         });
 
-        let res = futures::future::join_all(futs).await;
+        let res = futures::future::try_join_all(futs).await?;
         println!("{:#?}", res);
 
         let futs_rerank = res.iter().map(|(answer, _)| async {
             let mut his = Vec::new();
             let prompt = self
                 .prepare_prompt(&btreemap! {
-                    "question".to_string() => input["question"].clone(),
+                    "question".to_string() => question.clone(),
                     "answer".to_string() => answer.clone(),
                 })
-                .unwrap();
+                .ok_or_else(|| LlmError::InvalidRequest("MapRerankChain: cannot prepare the rerank prompt".to_string()))?;
+            if self.scoring == RerankScoring::Logprobs {
+                return self.score_by_logprobs(llm, prompt, answer, options.clone()).await;
+            }
             his.push(prompt);
-            let output = llm.generate(his, stop.clone()).await;
-            (output.text[0].content.clone(), output.info)
+            let (score, output) = llm
+                .generate_structured::<RerankScore>(his, RERANK_FORMAT, options.clone())
+                .await?;
+            Ok::<_, LlmError>((score, output.usage))
         });
 
-        let res_rerank = futures::future::join_all(futs_rerank).await;
+        let res_rerank = futures::future::try_join_all(futs_rerank).await?;
         println!("{:#?}", res_rerank);
 
-        let jsons = res_rerank
-            .iter()
-            .map(|(answer, _info)| {
-                let json = serde_json::from_str::<serde_json::Value>(answer).unwrap();
-                json
-            })
-            .collect_vec();
-
-        let max_score = jsons
-            .iter()
-            .max_by(|a, b| {
-                a["score"]
-                    .as_f64()
-                    .unwrap()
-                    .partial_cmp(&b["score"].as_f64().unwrap())
-                    .unwrap()
-            })
-            .unwrap();
-        Some(Generation {
+        let Some((max_score, _)) = res_rerank.iter().max_by(|(a, _), (b, _)| a.score.total_cmp(&b.score)) else {
+            return Ok(None);
+        };
+        Ok(Some(Generation {
             text: vec![Message {
-                role: "assistant".to_string(),
-                content: serde_json::to_string(max_score).unwrap(),
+                role: Role::Assistant,
+                content: serde_json::to_string(max_score).unwrap().into(),
+                ..Default::default()
             }],
+            usage: Usage::aggregate(
+                res.into_iter()
+                    .map(|(_, usage)| usage)
+                    .chain(res_rerank.into_iter().map(|(_, usage)| usage)),
+            ),
             info: None,
-        })
+            logprobs: vec![],
+        }))
     }
 }
 
@@ -146,8 +224,85 @@ async fn test_map_reduce() {
     let chain = MapRerankChain {
         prompt_template: None,
         map_chain,
+        scoring: Default::default(),
     };
 
-    let res = chain.generate(None, &executor, &inputs, vec![]).await;
+    let res = chain.generate(None, &executor, &inputs, vec![], Default::default()).await;
     println!("{:#?}", res);
 }
+
+#[tokio::test]
+async fn test_map_rerank_fake() {
+    use crate::chain::llm_chain::LLMChain;
+    use crate::llm::fake::FakeLLM;
+
+    let llm = FakeLLM::new(Vec::<&str>::new())
+        .on("^What is human", "human is a species")
+        .on("^What is programmer", "programmer writes programs")
+        .on(r"Doc: human", "```json\n{\"score\": 0.2, \"doc\": \"human is a species\"}\n```")
+        .on(r"Doc: programmer", r#"the score is {"score": 0.9, "doc": "programmer writes programs"}"#);
+    let chain = MapRerankChain {
+        prompt_template: None,
+        map_chain: LLMChain::new(Some(PromptTemplate::from("{question}".to_string()))),
+        scoring: Default::default(),
+    };
+    let inputs = btreemap! {
+        "question".to_string() => "what is a computer program".to_string(),
+        "1".to_string() => r#"{"question": "What is human?"}"#.to_string(),
+        "2".to_string() => r#"{"question": "What is programmer?"}"#.to_string(),
+    };
+
+    let res = chain.generate(None, &llm, &inputs, vec![], Default::default()).await.unwrap().unwrap();
+    let best: serde_json::Value = serde_json::from_str(&res.text[0].content.to_string()).unwrap();
+    assert_eq!(best["doc"], "programmer writes programs");
+    assert_eq!(llm.calls().len(), 4);
+}
+
+#[tokio::test]
+async fn test_map_rerank_logprobs() {
+    use crate::chain::llm_chain::LLMChain;
+    use crate::llm::fake::FakeLLM;
+    use crate::schema::logprob::{TokenLogprob, TopLogprob};
+
+    let answer = |yes: f64, no: f64| Generation {
+        text: vec![Message {
+            role: Role::Assistant,
+            content: "yes".into(),
+            ..Default::default()
+        }],
+        logprobs: vec![vec![TokenLogprob {
+            token: "yes".to_string(),
+            logprob: yes.ln(),
+            top_logprobs: vec![
+                TopLogprob { token: "yes".to_string(), logprob: yes.ln() },
+                TopLogprob { token: "No".to_string(), logprob: no.ln() },
+            ],
+        }]],
+        usage: None,
+        info: None,
+    };
+    // both answer yes, the programmer doc is the more certain one
+    let llm = FakeLLM::new(Vec::<&str>::new())
+        .on("^What is human", "human is a species")
+        .on("^What is programmer", "programmer writes programs")
+        .on(r"Doc: human", answer(0.55, 0.45))
+        .on(r"Doc: programmer", answer(0.9, 0.1));
+    let chain = MapRerankChain {
+        prompt_template: None,
+        map_chain: LLMChain::new(Some(PromptTemplate::from("{question}".to_string()))),
+        scoring: RerankScoring::Logprobs,
+    };
+    let inputs = btreemap! {
+        "question".to_string() => "what is a computer program".to_string(),
+        "1".to_string() => r#"{"question": "What is human?"}"#.to_string(),
+        "2".to_string() => r#"{"question": "What is programmer?"}"#.to_string(),
+    };
+
+    let res = chain.generate(None, &llm, &inputs, vec![], Default::default()).await.unwrap().unwrap();
+    let best: serde_json::Value = serde_json::from_str(&res.text[0].content.to_string()).unwrap();
+    assert_eq!(best["doc"], "programmer writes programs");
+    assert!((best["score"].as_f64().unwrap() - 0.9).abs() < 1e-9);
+    let rerank_call = llm.calls().into_iter().find(|call| call.prompt().contains("Doc: human")).unwrap();
+    assert_eq!(rerank_call.options.max_tokens, Some(1));
+    assert_eq!(rerank_call.options.top_logprobs, Some(5));
+}
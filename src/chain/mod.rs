@@ -1,3 +1,4 @@
+pub mod candidate_chain;
 pub mod character_chain;
 pub mod llm_chain;
 pub mod map_reduce;
@@ -5,17 +6,68 @@ pub mod map_rerank;
 pub mod seq_chain;
 
 use std::{
-    collections::{BTreeMap},
+    collections::{BTreeMap}, future::Future, ops::Index, time::Duration,
 };
 
 use serde::Serialize;
+use tokio_util::sync::CancellationToken;
 
 
 use crate::{
     prompt_template::PromptTemplate,
-    schema::{Generation, Message, memory::Memory}, llm::LLM,
+    schema::{Generation, Message, Role, memory::Memory, usage::Usage}, llm::{GenerateOptions, LLM, LlmError},
 };
 
+/// ChainOutput is the output of a chain run with the usage of all llm calls it made,
+/// `output["answer"]` can be written as `res["answer"]`
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainOutput {
+    pub output: BTreeMap<String, Message>,
+    /// None if no call reported usage
+    pub usage: Option<Usage>,
+}
+
+impl Index<&str> for ChainOutput {
+    type Output = Message;
+
+    fn index(&self, key: &str) -> &Message {
+        &self.output[key]
+    }
+}
+
+/// RunControl bounds a chain run from the outside, `timeout` is the deadline of the whole run
+/// and `cancel` stops it early, e.g. when the client disconnects. a stopped run drops every call
+/// still in flight, including the fan-out calls of MapReduceChain and MapRerankChain
+#[derive(Debug, Clone, Default)]
+pub struct RunControl {
+    pub timeout: Option<Duration>,
+    pub cancel: CancellationToken,
+}
+
+impl RunControl {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..Default::default()
+        }
+    }
+
+    /// run drives the future until it finishes, the deadline passes or the run is cancelled
+    pub async fn run<T>(&self, fut: impl Future<Output = Result<T, LlmError>>) -> Result<T, LlmError> {
+        let deadline = async {
+            match self.timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            res = fut => res,
+            _ = self.cancel.cancelled() => Err(LlmError::Cancelled),
+            _ = deadline => Err(LlmError::Timeout(self.timeout.unwrap_or_default())),
+        }
+    }
+}
+
 #[async_trait::async_trait]
 pub trait Chain: Serialize {
     // ----- prepare -----
@@ -25,43 +77,54 @@ pub trait Chain: Serialize {
     /// prepare_prompt function generates the prompt from the input
     fn prepare_prompt(&self, input: &BTreeMap<String, String>) -> Option<Message> {
         Some(Message {
-            role: "user".to_string(),
-            content: self.get_prompt_template().format(input)?,
+            role: Role::User,
+            content: self.get_prompt_template().format(input)?.into(),
+            ..Default::default()
         })
     }
     /// better override this
     fn create_output(&self, generation: Generation) -> Option<BTreeMap<String, Message>>;
 
     // ----- execute -----
-    /// generate function generates the output from the input and keeps in raw format
+    /// generate function generates the output from the input and keeps in raw format,
+    /// Ok(None) means the prompt cannot be prepared from the input
     async fn generate(
         &self,
         memory: Option<&Box<dyn Memory + Send + Sync>>,
         llm: &impl LLM,
         input: &BTreeMap<String, String>,
         stop: Vec<String>,
-    ) -> Option<Generation> {
-        let prompt = self.prepare_prompt(input);
+        options: GenerateOptions,
+    ) -> Result<Option<Generation>, LlmError> {
+        let Some(prompt) = self.prepare_prompt(input) else {
+            return Ok(None);
+        };
         if let Some(mem) = memory {
-            let mut his = mem.get_history().await.unwrap();
-            his.push(prompt?);
-            Some(llm.generate(his, stop).await)
+            let mut his = mem.get_history().await.map_err(|e| LlmError::Memory(e.to_string()))?;
+            his.push(prompt);
+            Ok(Some(llm.generate(his, stop, options).await?))
         } else {
             let mut his = Vec::new();
-            his.push(prompt?);
-            Some(llm.generate(his, stop).await)
+            his.push(prompt);
+            Ok(Some(llm.generate(his, stop, options).await?))
         }
     }
-    /// apply function generates the output from the input
+    /// apply function generates the output from the input, along with the total usage of the run
     async fn apply(
         &self,
         memory: Option<&Box<dyn Memory + Send + Sync>>,
         llm: &impl LLM,
         input: &BTreeMap<String, String>,
         stop: Vec<String>,
-    ) -> Option<BTreeMap<String, Message>> {
-        let generation = self.generate(memory, llm, input, stop).await;
-        Some(self.create_output(generation?)?)
+        options: GenerateOptions,
+    ) -> Result<Option<ChainOutput>, LlmError> {
+        let Some(generation) = self.generate(memory, llm, input, stop, options).await? else {
+            return Ok(None);
+        };
+        let usage = generation.usage;
+        Ok(self
+            .create_output(generation)
+            .map(|output| ChainOutput { output, usage }))
     }
     /// predict function generates the output from the input, default implementation is to call apply
     async fn predict(
@@ -70,7 +133,20 @@ pub trait Chain: Serialize {
         llm: &impl LLM,
         input: &BTreeMap<String, String>,
         stop: Vec<String>,
-    ) -> Option<BTreeMap<String, Message>> {
-        self.apply(memory, llm, input, stop).await
+        options: GenerateOptions,
+    ) -> Result<Option<ChainOutput>, LlmError> {
+        self.apply(memory, llm, input, stop, options).await
+    }
+    /// apply_with_control is apply bounded by the deadline and the cancellation of `control`
+    async fn apply_with_control(
+        &self,
+        memory: Option<&Box<dyn Memory + Send + Sync>>,
+        llm: &impl LLM,
+        input: &BTreeMap<String, String>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+        control: &RunControl,
+    ) -> Result<Option<ChainOutput>, LlmError> {
+        control.run(self.apply(memory, llm, input, stop, options)).await
     }
 }
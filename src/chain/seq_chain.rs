@@ -1,7 +1,7 @@
 use itertools::Itertools;
 use serde::Deserialize;
 
-use crate::schema::memory::Memory;
+use crate::schema::{memory::Memory, usage::Usage, Role};
 
 use super::*;
 
@@ -57,21 +57,33 @@ question:
         llm: &impl LLM,
         input: &BTreeMap<String, String>,
         stop: Vec<String>,
-    ) -> Option<Generation> {
-        let previous_output = self
+        options: GenerateOptions,
+    ) -> Result<Option<Generation>, LlmError> {
+        let Some(previous_output) = self
             .chain1
-            .generate(memory, llm, input, stop.clone())
-            .await?;
-        let question = self.chain2.prepare_prompt(input)?;
-        let prompt = self
+            .generate(memory, llm, input, stop.clone(), options.clone())
+            .await?
+        else {
+            return Ok(None);
+        };
+        let Some(question) = self.chain2.prepare_prompt(input) else {
+            return Ok(None);
+        };
+        let Some(previous_answer) = previous_output.text.first() else {
+            return Ok(None);
+        };
+        let Some(prompt) = self
             .get_prompt_template()
             .format(&BTreeMap::from_iter(vec![
                 (
                     "previous_output".to_string(),
-                    previous_output.text[0].content.clone(),
+                    previous_answer.content.to_string(),
                 ),
-                ("question".to_string(), question.content),
-            ]))?;
+                ("question".to_string(), question.content.to_string()),
+            ]))
+        else {
+            return Ok(None);
+        };
 
         // debug
         println!("prompt: {}", prompt);
@@ -79,19 +91,22 @@ question:
         let mut prompt = vec![
             // Message::from_str("system: using background to answer the following question").unwrap(),
             Message {
-                role: "user".to_string(),
-                content: prompt,
+                role: Role::User,
+                content: prompt.into(),
+                ..Default::default()
             },
         ];
 
-        match memory {
+        let mut generation = match memory {
             Some(mem) => {
-                let mut his = mem.get_history().await.unwrap();
+                let mut his = mem.get_history().await.map_err(|e| LlmError::Memory(e.to_string()))?;
                 his.append(&mut prompt);
-                Some(llm.generate(his, stop).await)
+                llm.generate(his, stop, options).await?
             }
-            None => Some(llm.generate(prompt, stop).await),
-        }
+            None => llm.generate(prompt, stop, options).await?,
+        };
+        generation.usage = Usage::aggregate([previous_output.usage, generation.usage]);
+        Ok(Some(generation))
     }
 
     fn create_output(&self, generation: Generation) -> Option<BTreeMap<String, Message>> {
@@ -124,6 +139,7 @@ async fn test_bind_chain() {
                 "question2".to_string() => question2.to_string(),
             },
             vec!["stop".to_string()],
+            Default::default(),
         )
         .await;
 
@@ -154,3 +170,40 @@ fn serde_deserde() {
     let chain: SeqChain<LLMChain, LLMChain> = serde_json::from_str(&str).unwrap();
     println!("{:#?}", chain);
 }
+
+#[tokio::test]
+async fn test_seq_chain_fake() {
+    use super::llm_chain::*;
+    use crate::btreemap;
+    use crate::llm::fake::FakeLLM;
+
+    let llm = FakeLLM::new(["LGTM means looks good to me", "看起来不错"]);
+    let chain1 = LLMChain::new(Some(PromptTemplate::from("{question1}".to_string())));
+    let chain2 = LLMChain::new(Some(PromptTemplate::from("{question2}".to_string())));
+    let chain = SeqChain::new(None, chain1, chain2);
+
+    let res = chain
+        .apply(
+            None,
+            &llm,
+            &btreemap! {
+                "question1".to_string() => "what does LGTM mean?".to_string(),
+                "question2".to_string() => "translate it to chinese".to_string(),
+            },
+            vec!["stop".to_string()],
+            Default::default(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(res["answer"].content, "看起来不错");
+
+    let calls = llm.calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0].prompt(), "what does LGTM mean?");
+    assert_eq!(
+        calls[1].prompt(),
+        "\nbackground:\nLGTM means looks good to me\n\nquestion:\ntranslate it to chinese\n"
+    );
+    assert_eq!(calls[1].stop, vec!["stop".to_string()]);
+}
@@ -1,3 +1,4 @@
 pub mod loader;
 pub mod persistent;
 pub mod splitter;
+pub mod text_embedding;
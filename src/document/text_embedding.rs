@@ -1,3 +1,189 @@
-pub struct Cache {
-    pub cache: BTreeMap<String, String>,
+use std::{collections::BTreeMap, path::Path};
+
+use itertools::Itertools;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::llm::Embedding;
+
+/// Cache wraps an embedding model and keeps every vector it produced on disk,
+/// so unchanged texts are not embedded again. entries are keyed by the model
+/// (name, `model` and dimension) and a sha256 of the text
+#[derive(Debug, Serialize)]
+pub struct Cache<E: Embedding> {
+    pub embedding: E,
+    #[serde(skip)]
+    db: sled::Db,
+    #[serde(skip)]
+    model: String,
+}
+
+/// model_key names the vectors of an embedding client by its name, the `model` field it serializes
+/// and the dimension, transport settings like the batch size do not change the vectors.
+/// clients without a `model` field are keyed by all their parameters
+fn model_key(embedding: &impl Embedding) -> anyhow::Result<String> {
+    let params = serde_json::to_value(embedding)?;
+    let model = params.get("model").unwrap_or(&params);
+    Ok(format!("{}/{}/{}", embedding.name(), model, embedding.dimension()))
+}
+
+impl<E: Embedding> Cache<E> {
+    /// open opens (or creates) the cache database at path
+    pub fn open(embedding: E, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let model = model_key(&embedding)?;
+        Ok(Self {
+            embedding,
+            db: sled::open(path)?,
+            model,
+        })
+    }
+
+    fn key(&self, input: &str) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(self.model.as_bytes());
+        hasher.update([0]);
+        hasher.update(input.as_bytes());
+        hasher.finalize().to_vec()
+    }
+
+    /// get returns the cached vector of the text
+    pub fn get(&self, input: &str) -> Option<Vec<f32>> {
+        match self.db.get(self.key(input)) {
+            Ok(value) => Some(
+                value?
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect(),
+            ),
+            Err(e) => {
+                println!("Warning: Cache: {}", e);
+                None
+            }
+        }
+    }
+
+    /// get_batch looks up many texts at once, the result is in input order
+    pub fn get_batch(&self, inputs: &[String]) -> Vec<Option<Vec<f32>>> {
+        inputs.iter().map(|input| self.get(input)).collect()
+    }
+
+    pub fn put(&self, input: &str, vector: &[f32]) {
+        let value = vector.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+        if let Err(e) = self.db.insert(self.key(input), value) {
+            println!("Warning: Cache: {}", e);
+        }
+    }
+
+    /// flush writes pending entries to disk, they are also flushed periodically and on drop
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        self.db.flush_async().await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<E: Embedding> Embedding for Cache<E> {
+    fn name(&self) -> &'static str {
+        self.embedding.name()
+    }
+
+    fn dimension(&self) -> usize {
+        self.embedding.dimension()
+    }
+
+    async fn encode(&self, input: String) -> Vec<f32> {
+        if let Some(vector) = self.get(&input) {
+            return vector;
+        }
+        let vector = self.embedding.encode(input.clone()).await;
+        self.put(&input, &vector);
+        vector
+    }
+
+    /// only the texts missing in the cache are sent to the embedding model, each distinct text once
+    async fn encode_batch(&self, inputs: Vec<String>) -> Vec<Vec<f32>> {
+        let cached = self.get_batch(&inputs);
+        let missing = inputs
+            .iter()
+            .zip(&cached)
+            .filter(|(_, vector)| vector.is_none())
+            .map(|(input, _)| input.clone())
+            .unique()
+            .collect::<Vec<_>>();
+        let vectors = self.embedding.encode_batch(missing.clone()).await;
+        for (input, vector) in missing.iter().zip(&vectors) {
+            self.put(input, vector);
+        }
+        let encoded = missing.into_iter().zip(vectors).collect::<BTreeMap<_, _>>();
+
+        inputs
+            .iter()
+            .zip(cached)
+            .map(|(input, vector)| vector.unwrap_or_else(|| encoded[input].clone()))
+            .collect()
+    }
+}
+
+#[tokio::test]
+async fn test_embedding_cache() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[derive(Serialize)]
+    struct LenEmbedding {
+        model: String,
+        batch_size: usize,
+        #[serde(skip)]
+        calls: AtomicU32,
+    }
+
+    #[async_trait::async_trait]
+    impl Embedding for LenEmbedding {
+        fn name(&self) -> &'static str {
+            "Len"
+        }
+        fn dimension(&self) -> usize {
+            2
+        }
+        async fn encode(&self, input: String) -> Vec<f32> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            vec![input.len() as f32, 0.5]
+        }
+    }
+
+    let path = std::env::temp_dir().join(format!("limitchain_embedding_{}", std::process::id()));
+    let embedding = |model: &str| LenEmbedding {
+        model: model.to_string(),
+        batch_size: 16,
+        calls: AtomicU32::new(0),
+    };
+    let inputs = vec!["a".to_string(), "bb".to_string(), "a".to_string()];
+
+    {
+        let cache = Cache::open(embedding("small"), &path).unwrap();
+        assert_eq!(cache.encode("bb".to_string()).await, vec![2.0, 0.5]);
+        let vectors = cache.encode_batch(inputs.clone()).await;
+        assert_eq!(vectors, vec![vec![1.0, 0.5], vec![2.0, 0.5], vec![1.0, 0.5]]);
+        assert_eq!(cache.embedding.calls.load(Ordering::SeqCst), 2);
+        cache.flush().await.unwrap();
+    }
+
+    // reopened from disk with another batch size, nothing needs to be embedded again
+    let cache = Cache::open(
+        LenEmbedding {
+            batch_size: 64,
+            ..embedding("small")
+        },
+        &path,
+    )
+    .unwrap();
+    assert!(cache.get_batch(&inputs).iter().all(Option::is_some));
+    cache.encode_batch(inputs.clone()).await;
+    assert_eq!(cache.embedding.calls.load(Ordering::SeqCst), 0);
+    drop(cache);
+
+    // another model does not share the vectors
+    let cache = Cache::open(embedding("large"), &path).unwrap();
+    assert!(cache.get("a").is_none());
+    drop(cache);
+    std::fs::remove_dir_all(&path).unwrap();
 }
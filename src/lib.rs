@@ -1,11 +1,10 @@
-#![feature(return_position_impl_trait_in_trait)]
-#![feature(once_cell)]
 pub mod chain;
 pub mod llm;
 pub mod document;
 pub mod parser;
 pub mod prompt_template;
 pub mod schema;
+pub mod tokenizer;
 pub mod vectordb;
 
 
@@ -0,0 +1,220 @@
+use std::{num::NonZeroUsize, path::Path, sync::Mutex};
+
+use lru::LruCache;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::schema::{usage::Usage, Generation, Message};
+
+use super::{GenerateOptions, LlmError, LLM};
+
+/// request_key is the hex sha256 of the llm name, its serialized parameters (including the api base of the server),
+/// the messages and the stop sequences, two calls with the same key get the same answer
+pub(crate) fn request_key(llm: &impl LLM, input: &[Message], stop: &[String], options: &GenerateOptions) -> String {
+    let request = serde_json::json!({
+        "llm": llm.name(),
+        "params": serde_json::to_value(llm).unwrap_or_default(),
+        "messages": input,
+        "stop": stop,
+        "options": options,
+    });
+    Sha256::digest(request.to_string().as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// LLMCache is a storage backend of CachedLLM
+pub trait LLMCache: Send + Sync {
+    fn get(&self, key: &str) -> Option<Generation>;
+    fn put(&self, key: &str, generation: &Generation);
+}
+
+/// InMemoryCache keeps the most recently used generations in memory
+#[derive(Debug)]
+pub struct InMemoryCache {
+    cache: Mutex<LruCache<String, Generation>>,
+}
+
+impl InMemoryCache {
+    /// new creates a cache holding at most `capacity` generations (at least 1)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cache: Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN),
+            )),
+        }
+    }
+}
+
+impl LLMCache for InMemoryCache {
+    fn get(&self, key: &str) -> Option<Generation> {
+        self.cache.lock().unwrap().get(key).cloned()
+    }
+
+    fn put(&self, key: &str, generation: &Generation) {
+        self.cache
+            .lock()
+            .unwrap()
+            .put(key.to_string(), generation.clone());
+    }
+}
+
+/// SledCache keeps generations in a sled database on disk, so they survive restarts
+#[derive(Debug, Clone)]
+pub struct SledCache {
+    db: sled::Db,
+}
+
+impl SledCache {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(Self {
+            db: sled::open(path)?,
+        })
+    }
+}
+
+/// storage failures are reported and treated as a miss, the cache never fails a call
+impl LLMCache for SledCache {
+    fn get(&self, key: &str) -> Option<Generation> {
+        match self.db.get(key) {
+            Ok(value) => serde_json::from_slice(&value?).ok(),
+            Err(e) => {
+                println!("Warning: SledCache: {}", e);
+                None
+            }
+        }
+    }
+
+    fn put(&self, key: &str, generation: &Generation) {
+        let res = serde_json::to_vec(generation)
+            .map_err(anyhow::Error::from)
+            .and_then(|value| Ok(self.db.insert(key, value)?));
+        if let Err(e) = res {
+            println!("Warning: SledCache: {}", e);
+        }
+    }
+}
+
+/// CachedLLM answers a call from the cache when the same call (same client parameters,
+/// messages and stop sequences) was answered before, `info.cache` is set to `hit` or `miss`.
+/// a hit reports a zero usage, so the spend of a chain only counts the calls really sent
+#[derive(Debug, Serialize)]
+pub struct CachedLLM<L: LLM, C: LLMCache> {
+    pub llm: L,
+    #[serde(skip)]
+    pub cache: C,
+}
+
+impl<L: LLM, C: LLMCache> CachedLLM<L, C> {
+    pub fn new(llm: L, cache: C) -> Self {
+        Self { llm, cache }
+    }
+}
+
+#[async_trait::async_trait]
+impl<L: LLM, C: LLMCache> LLM for CachedLLM<L, C> {
+    fn name(&self) -> &'static str {
+        self.llm.name()
+    }
+
+    async fn generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        let key = request_key(&self.llm, &input, &stop, &options);
+        if let Some(mut generation) = self.cache.get(&key) {
+            generation.usage = Some(Usage::free());
+            generation.set_info("cache", "hit");
+            return Ok(generation);
+        }
+        // failed calls are not cached
+        let mut generation = self.llm.generate(input, stop, options).await?;
+        self.cache.put(&key, &generation);
+        generation.set_info("cache", "miss");
+        Ok(generation)
+    }
+}
+
+#[tokio::test]
+async fn test_cached_llm() {
+    use super::fake::FakeLLM;
+    use crate::schema::Role;
+
+    let ask = |text: &str| {
+        vec![Message {
+            role: Role::User,
+            content: text.into(),
+            ..Default::default()
+        }]
+    };
+
+    let mut fake = FakeLLM::new(["a", "b", "c", "d"]);
+    fake.usage = Some(Usage {
+        prompt_tokens: 10,
+        completion_tokens: 5,
+        cost: Some(0.01),
+    });
+    let llm = CachedLLM::new(fake, InMemoryCache::new(1));
+    let res = llm.generate(ask("1"), vec![], Default::default()).await.unwrap();
+    assert_eq!(res.text[0].content, "a");
+    assert_eq!(res.info.unwrap()["cache"], "miss");
+    assert_eq!(res.usage.unwrap().cost, Some(0.01));
+    let res = llm.generate(ask("1"), vec![], Default::default()).await.unwrap();
+    assert_eq!(res.text[0].content, "a");
+    assert_eq!(res.info.unwrap()["cache"], "hit");
+    // nothing was spent on the hit
+    assert_eq!(res.usage.unwrap().total_tokens(), 0);
+    assert_eq!(res.usage.unwrap().cost, Some(0.0));
+    // another stop list is another call
+    let res = llm.generate(ask("1"), vec!["stop".to_string()], Default::default()).await.unwrap();
+    assert_eq!(res.text[0].content, "b");
+    // capacity is 1, so the first answer is evicted
+    let res = llm.generate(ask("1"), vec![], Default::default()).await.unwrap();
+    assert_eq!(res.text[0].content, "c");
+    // other options are another call too
+    let cold = GenerateOptions {
+        temperature: Some(0.0),
+        ..Default::default()
+    };
+    let res = llm.generate(ask("1"), vec![], cold).await.unwrap();
+    assert_eq!(res.text[0].content, "d");
+    assert_eq!(llm.llm.calls().len(), 4);
+
+    let path = std::env::temp_dir().join(format!("limitchain_sled_{}", std::process::id()));
+    {
+        let llm = CachedLLM::new(FakeLLM::new(["a"]), SledCache::open(&path).unwrap());
+        llm.generate(ask("1"), vec![], Default::default()).await.unwrap();
+    }
+    let llm = CachedLLM::new(FakeLLM::default(), SledCache::open(&path).unwrap());
+    let res = llm.generate(ask("1"), vec![], Default::default()).await.unwrap();
+    assert_eq!(res.text[0].content, "a");
+    assert_eq!(res.info.unwrap()["cache"], "hit");
+    drop(llm);
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_request_key() {
+    use super::client::openai::OpenAIClient;
+
+    let openai = OpenAIClient {
+        model: "llama3".to_string(),
+        ..Default::default()
+    };
+    let mut local = openai.clone();
+    local.config.api_base = "http://localhost:8080/v1".to_string();
+    let key = |llm: &OpenAIClient| request_key(llm, &[], &[], &Default::default());
+    // the same model on another server is another answer, the api key is not part of it
+    assert_ne!(key(&openai), key(&local));
+    let mut rotated = openai.clone();
+    rotated.config.api_key = "sk-other".to_string();
+    assert_eq!(key(&openai), key(&rotated));
+    assert!(!serde_json::to_string(&rotated).unwrap().contains("sk-other"));
+    // the api base survives a round trip, so does the key
+    let loaded: OpenAIClient = serde_json::from_str(&serde_json::to_string(&local).unwrap()).unwrap();
+    assert_eq!(loaded.config.api_base, "http://localhost:8080/v1");
+    assert_eq!(key(&loaded), key(&local));
+}
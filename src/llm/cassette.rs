@@ -0,0 +1,162 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::schema::{usage::Usage, Generation, Message};
+
+use super::{cache::request_key, GenerateOptions, LlmError, LLM};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CassetteMode {
+    /// every call goes to the wrapped llm and its answer is written to the cassette
+    Record,
+    /// every call is answered from the cassette, a call that was never recorded panics
+    Replay,
+}
+
+/// one recorded call, the request is kept to make the cassette readable and diffable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CassetteEntry {
+    pub llm: String,
+    pub messages: Vec<Message>,
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub options: GenerateOptions,
+    pub generation: Generation,
+}
+
+fn is_default(options: &GenerateOptions) -> bool {
+    *options == GenerateOptions::default()
+}
+
+/// CassetteLLM records the answers of the wrapped llm to a json file and replays them later,
+/// so end to end tests can run without network. calls are keyed by a hash of the messages,
+/// the stop sequences, the options and the serialized client parameters
+#[derive(Debug, Serialize)]
+pub struct CassetteLLM<L: LLM> {
+    pub llm: L,
+    #[serde(skip)]
+    pub path: PathBuf,
+    #[serde(skip)]
+    pub mode: CassetteMode,
+    #[serde(skip)]
+    entries: Mutex<BTreeMap<String, CassetteEntry>>,
+}
+
+impl<L: LLM> CassetteLLM<L> {
+    /// new replays the cassette at path if it exists and records a new one otherwise,
+    /// panics if the existing cassette cannot be read
+    pub fn new(llm: L, path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let (mode, entries) = match std::fs::read_to_string(&path) {
+            Ok(s) => (
+                CassetteMode::Replay,
+                serde_json::from_str(&s)
+                    .unwrap_or_else(|e| panic!("CassetteLLM: broken cassette {}: {}", path.display(), e)),
+            ),
+            Err(_) => (CassetteMode::Record, BTreeMap::new()),
+        };
+        Self {
+            llm,
+            path,
+            mode,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    fn save(&self, entries: &BTreeMap<String, CassetteEntry>) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.path, serde_json::to_string_pretty(entries)?)
+    }
+}
+
+#[async_trait::async_trait]
+impl<L: LLM> LLM for CassetteLLM<L> {
+    fn name(&self) -> &'static str {
+        self.llm.name()
+    }
+
+    async fn generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        let key = request_key(&self.llm, &input, &stop, &options);
+        if self.mode == CassetteMode::Replay {
+            let entry = self.entries.lock().unwrap().get(&key).cloned();
+            let Some(entry) = entry else {
+                panic!(
+                    "CassetteLLM: no recorded answer in {} for {} call with messages {:?}, stop {:?} and options {:?}, \
+                     delete the cassette to record it again",
+                    self.path.display(),
+                    self.llm.name(),
+                    input,
+                    stop,
+                    options
+                );
+            };
+            // nothing is spent on a replayed answer
+            let mut generation = entry.generation;
+            generation.usage = Some(Usage::free());
+            return Ok(generation);
+        }
+
+        // failed calls are not recorded, they are returned as they are
+        let generation = self.llm.generate(input.clone(), stop.clone(), options.clone()).await?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(
+            key,
+            CassetteEntry {
+                llm: self.llm.name().to_string(),
+                messages: input,
+                stop,
+                options,
+                generation: generation.clone(),
+            },
+        );
+        if let Err(e) = self.save(&entries) {
+            println!("Warning: CassetteLLM: cannot write {}: {}", self.path.display(), e);
+        }
+        Ok(generation)
+    }
+}
+
+#[tokio::test]
+async fn test_cassette_llm() {
+    use super::fake::FakeLLM;
+    use crate::schema::Role;
+
+    let path = std::env::temp_dir().join(format!("limitchain_cassette_{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let input = vec![Message {
+        role: Role::User,
+        content: "hi".into(),
+        ..Default::default()
+    }];
+
+    let llm = CassetteLLM::new(FakeLLM::new(["hello"]), &path);
+    assert_eq!(llm.mode, CassetteMode::Record);
+    let recorded = llm.generate(input.clone(), vec![], Default::default()).await.unwrap();
+    assert_eq!(recorded.text[0].content, "hello");
+
+    // the fake has nothing scripted, the answer has to come from the cassette
+    let llm = CassetteLLM::new(FakeLLM::default(), &path);
+    assert_eq!(llm.mode, CassetteMode::Replay);
+    let replayed = llm.generate(input.clone(), vec![], Default::default()).await.unwrap();
+    assert_eq!(replayed.text[0].content, "hello");
+    assert!(llm.llm.calls().is_empty());
+
+    let miss = futures::FutureExt::catch_unwind(std::panic::AssertUnwindSafe(
+        llm.generate(input, vec!["stop".to_string()], Default::default()),
+    ))
+    .await;
+    assert!(miss.is_err());
+    std::fs::remove_file(&path).unwrap();
+}
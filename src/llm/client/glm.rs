@@ -1,50 +1,265 @@
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use jwt::{AlgorithmType, JoseHeader, SignWithKey, Token};
 use serde::{Serialize, Deserialize};
 use serde_json::json;
-use zhipuai_sdk_rust::models::*;
-use zhipuai_sdk_rust::models::characterglm::CharacterGLMMeta;
-use crate::parser::unescape;
-use crate::schema::{Message, Generation};
-use crate::llm::{LLM, Embedding};
-use zhipuai_sdk_rust::models::chatglm::ChatGLMInvokeParam;
+use sha2::Sha256;
+use crate::schema::{content::{Content, ContentPart}, Message, Generation, Role};
+use crate::llm::{
+    error::retry_after_message, options::check_range, Embedding, GenerateOptions, GenerationStream, LlmError,
+    ResponseFormat, LLM,
+};
+
+use super::openai_api::{
+    create_chat_completion, create_chat_completion_stream, create_embeddings, encode_in_chunks, response_format_to_json,
+    ChatCompletionRequest, ChatMessage, EmbeddingRequest,
+};
+
+const GLM_API_BASE: &str = "https://open.bigmodel.cn/api/paas/v4";
+const GLM_TOKEN_TTL_MS: u128 = 30 * 60 * 1000;
+
+/// GLM requires a custom `sign_type` field in the jwt header
+#[derive(Serialize)]
+struct GLMTokenHeader {
+    alg: AlgorithmType,
+    sign_type: &'static str,
+}
+
+impl JoseHeader for GLMTokenHeader {
+    fn algorithm_type(&self) -> AlgorithmType {
+        self.alg
+    }
+}
+
+/// generate_token signs a short living jwt from an api key in `{id}.{secret}` format
+fn generate_token(api_key: &str) -> anyhow::Result<String> {
+    let (id, secret) = api_key
+        .split_once('.')
+        .ok_or_else(|| anyhow::anyhow!("invalid GLM api key"))?;
+    let key: Hmac<Sha256> = Hmac::new_from_slice(secret.as_bytes())?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let header = GLMTokenHeader {
+        alg: AlgorithmType::Hs256,
+        sign_type: "SIGN",
+    };
+    let claims = json!({
+        "api_key": id,
+        "exp": timestamp + GLM_TOKEN_TTL_MS,
+        "timestamp": timestamp,
+    });
+    let token = Token::new(header, claims).sign_with_key(&key)?;
+    Ok(token.as_str().to_string())
+}
+
+/// message_to_glm maps a message to the GLM prompt format, GLM only knows `system`, `user` and `assistant`,
+/// tool answers speak as user, custom roles too with their name prefixed to the content.
+/// images are only accepted by vision models, which take them as `image_url` parts
+fn message_to_glm(message: &Message, vision: bool) -> Result<ChatMessage, LlmError> {
+    let (role, prefix) = match &message.role {
+        Role::System => ("system", None),
+        Role::Assistant => ("assistant", None),
+        Role::User | Role::Tool => ("user", None),
+        Role::Custom(name) => ("user", Some(name)),
+    };
+    let content = match &message.content {
+        Content::Parts(parts) if message.content.has_images() => {
+            if !vision {
+                return Err(LlmError::InvalidRequest(
+                    "images are only supported by GLM vision models".to_string(),
+                ));
+            }
+            let mut parts = parts
+                .iter()
+                .map(|part| match part {
+                    ContentPart::Text { text } => json!({"type": "text", "text": text}),
+                    ContentPart::ImageUrl { url } => json!({"type": "image_url", "image_url": {"url": url}}),
+                    ContentPart::ImageBase64 { data, .. } => json!({"type": "image_url", "image_url": {"url": data}}),
+                })
+                .collect::<Vec<_>>();
+            if let Some(name) = prefix {
+                parts.insert(0, json!({"type": "text", "text": format!("{}:", name)}));
+            }
+            json!(parts)
+        }
+        content => match prefix {
+            Some(name) => json!(format!("{}: {}", name, content)),
+            None => json!(content.to_string()),
+        },
+    };
+    Ok(ChatMessage {
+        role: role.to_string(),
+        content: Some(content),
+        name: None,
+        tool_calls: None,
+        tool_call_id: None,
+    })
+}
+
+/// glm_error classifies the error codes of the GLM api, None for codes it does not know
+pub(crate) fn glm_error(code: &str, message: &str) -> Option<LlmError> {
+    let message = message.to_string();
+    Some(match code.parse::<i64>().ok()? {
+        1000..=1004 => LlmError::Auth(message),
+        1113 | 1304 => LlmError::QuotaExceeded(message),
+        1261 => LlmError::ContextLengthExceeded(message),
+        1302 | 1303 | 1305 => LlmError::RateLimited {
+            retry_after: retry_after_message(&message),
+            message,
+        },
+        1100..=1399 => LlmError::InvalidRequest(message),
+        _ => return None,
+    })
+}
+
+/// glm_api_key resolves the key of a client, `ZHIPUAI_API_KEY` is used when not set
+fn glm_api_key(api_key: &Option<String>) -> Result<String, LlmError> {
+    api_key
+        .clone()
+        .map_or_else(|| std::env::var("ZHIPUAI_API_KEY"), Ok)
+        .map_err(|_| LlmError::Auth("ZHIPUAI_API_KEY is not set".to_string()))
+}
+
+/// glm_request_builder targets `path` of the v4 api with a freshly signed token
+fn glm_request_builder(
+    client: &OnceLock<reqwest::Client>,
+    api_key: &Option<String>,
+    path: &str,
+) -> Result<reqwest::RequestBuilder, LlmError> {
+    let token = generate_token(&glm_api_key(api_key)?).map_err(|e| LlmError::Auth(e.to_string()))?;
+    Ok(client
+        .get_or_init(reqwest::Client::new)
+        .post(format!("{}{}", GLM_API_BASE, path))
+        .bearer_auth(token))
+}
+
+/// truncate_at_stop cuts the text before the first stop sequence
+fn truncate_at_stop(text: &str, stop: &[String]) -> Option<String> {
+    stop.iter()
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| text.find(s.as_str()))
+        .min()
+        .map(|i| text[..i].to_string())
+}
+
+/// CharacterGLMMeta is the persona of a characterglm model
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CharacterGLMMeta {
+    pub user_info: String,
+    pub bot_info: String,
+    pub bot_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_name: Option<String>,
+}
 
+/// GLMClient talks to the Zhipu GLM v4 chat completions api, any model name it serves is accepted,
+/// e.g. glm-4, glm-4-air, glm-4v or charglm-3
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct GLMClient {
-    pub invoke_param: serde_json::Value,
     pub model: String,
+    pub temperature: Option<f32>, // min: 0, max: 1, exclusive
+    pub top_p: Option<f32>,       // min: 0, max: 1, exclusive
+    pub max_tokens: Option<u16>,
+    /// persona of characterglm models, see `as_character`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<CharacterGLMMeta>,
+
+    /// `{id}.{secret}`, `ZHIPUAI_API_KEY` is used when not set
+    #[serde(skip)]
+    pub api_key: Option<String>,
+    #[serde(skip)]
+    pub(crate) client: OnceLock<reqwest::Client>,
 }
 
 impl Default for GLMClient {
     fn default() -> Self {
         Self {
-            invoke_param: json!(ChatGLMInvokeParam::default()),
-            model: "chatglm_turbo".to_string(),
+            model: "glm-4".to_string(),
+            temperature: Default::default(),
+            top_p: Default::default(),
+            max_tokens: Default::default(),
+            meta: Default::default(),
+            api_key: Default::default(),
+            client: Default::default(),
         }
     }
 }
 
 impl GLMClient {
-    pub fn as_character(mut self, meta: CharacterGLMMeta) -> Self {
-        self.invoke_param.as_object_mut().unwrap().insert("meta".to_string(), json!(meta));
+    pub fn new(model: impl Into<String>) -> Self {
         Self {
-            model: "characterglm".to_string(),
-            invoke_param: self.invoke_param,
+            model: model.into(),
+            ..Default::default()
         }
     }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct GLMEmbeddingClient {
-}
+    /// supports_vision tells if the model accepts images, e.g. glm-4v
+    pub fn supports_vision(&self) -> bool {
+        self.model.starts_with("glm-4v")
+    }
 
-#[async_trait::async_trait]
-impl Embedding for GLMEmbeddingClient {
-    fn name(&self) -> &'static str {
-        "ChatGLM Text Embedding"
+    pub fn as_character(mut self, meta: CharacterGLMMeta) -> Self {
+        self.model = "charglm-3".to_string();
+        self.meta = Some(meta);
+        self
     }
-    async fn encode(&self, input: String) -> Vec<f32> {
-        let model = Model::TextEmbedding;
-        let res = model.invoke(InvokeMeta { prompt: json!(input), invoke_param: json!({}) }).await;
-        res["data"]["embedding"].as_array().unwrap().into_iter().map(|x| x.as_f64().unwrap() as f32).collect()
+
+    /// create_request sends at most one stop sequence, GLM does not accept more.
+    /// GLM samples with temperature up to 1 and top_p strictly between 0 and 1, a temperature of 0 is sent
+    /// as greedy decoding (`do_sample: false`), it has no seed, no penalties, no logprobs and a single choice,
+    /// json schemas are asked for as plain json objects
+    fn create_request(
+        &self,
+        input: &[Message],
+        stop: &[String],
+        options: GenerateOptions,
+    ) -> Result<ChatCompletionRequest, LlmError> {
+        options.validate()?;
+        check_range("temperature", options.temperature, 0.0..=1.0)?;
+        check_range("top_p", options.top_p, 0.0..=1.0)?;
+        if let Some(top_p) = options.top_p.filter(|top_p| *top_p == 0.0 || *top_p == 1.0) {
+            return Err(LlmError::InvalidRequest(format!(
+                "top_p must be strictly between 0 and 1 for GLM, got {}",
+                top_p
+            )));
+        }
+        if options.seed.is_some() || options.presence_penalty.is_some() || options.frequency_penalty.is_some() {
+            println!("Warning: GLMClient: seed and penalties are not supported, ignored");
+        }
+        if options.logprobs == Some(true) || options.top_logprobs.is_some() {
+            println!("Warning: GLMClient: logprobs are not supported, ignored");
+        }
+        let response_format = options.response_format.and_then(|format| match format {
+            ResponseFormat::JsonSchema { .. } => Some(json!({"type": "json_object"})),
+            format => response_format_to_json(&format),
+        });
+
+        let mut extra = serde_json::Map::new();
+        if let Some(meta) = &self.meta {
+            extra.insert("meta".to_string(), json!(meta));
+        }
+        // GLM rejects a temperature of 0, greedy decoding is asked for by turning sampling off
+        let temperature = options.temperature.or(self.temperature);
+        if temperature == Some(0.0) {
+            extra.insert("do_sample".to_string(), json!(false));
+        }
+        Ok(ChatCompletionRequest {
+            model: self.model.clone(),
+            messages: input
+                .iter()
+                .map(|message| message_to_glm(message, self.supports_vision()))
+                .collect::<Result<_, _>>()?,
+            stop: stop.iter().take(1).cloned().collect(),
+            max_tokens: options.max_tokens.or(self.max_tokens),
+            temperature: temperature.filter(|temperature| *temperature > 0.0),
+            top_p: options.top_p.or(self.top_p),
+            response_format,
+            timeout: options.timeout,
+            extra,
+            ..Default::default()
+        })
     }
 }
 
@@ -53,29 +268,117 @@ impl LLM for GLMClient {
     fn name(&self) -> &'static str {
         "ChatGLM"
     }
-    async fn generate(&self, input: Vec<Message>, _stop: Vec<String>) -> Generation {
-        let invoke_prompt = json!(input);
 
+    /// stop sequences after the first are applied to the answer
+    async fn generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        let builder = glm_request_builder(&self.client, &self.api_key, "/chat/completions")?;
+        let mut generation = create_chat_completion(builder, &self.create_request(&input, &stop, options)?).await?;
+        for message in generation.text.iter_mut() {
+            if let Some(text) = truncate_at_stop(&message.content.to_string(), &stop) {
+                message.content = text.into();
+            }
+        }
+        Ok(generation)
+    }
 
-        let model = match self.model.as_str() {
-            "chatglm_130b" => Model::ChatGLM130b,
-            "chatglm_6b" => Model::ChatGLM6b,
-            "chatglm_turbo" => Model::ChatGLMTurbo,
-            "characterglm" => Model::CharacterGLM,
-            _ => panic!("unknown model")
+    /// only the first stop sequence is applied to a stream
+    async fn generate_stream(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> GenerationStream {
+        if stop.len() > 1 {
+            println!("Warning: GLMClient: only the first stop sequence is applied to a stream");
+        }
+        let request = glm_request_builder(&self.client, &self.api_key, "/chat/completions")
+            .and_then(|builder| Ok((builder, self.create_request(&input, &stop, options)?)));
+        match request {
+            Ok((builder, request)) => create_chat_completion_stream(builder, request),
+            Err(e) => Box::pin(futures::stream::once(async move { Err(e) })),
+        }
+    }
+}
+
+// ====== Embedding ======
+
+/// GLMEmbeddingClient embeds texts with the GLM v4 embeddings api
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GLMEmbeddingClient {
+    pub model: String,
+    /// shortens the vectors, only supported by embedding-3
+    pub dimensions: Option<u32>,
+    /// how many texts are sent in one request, GLM accepts up to 64
+    pub batch_size: usize,
+
+    /// `{id}.{secret}`, `ZHIPUAI_API_KEY` is used when not set
+    #[serde(skip)]
+    pub api_key: Option<String>,
+    #[serde(skip)]
+    pub(crate) client: OnceLock<reqwest::Client>,
+}
+
+impl Default for GLMEmbeddingClient {
+    fn default() -> Self {
+        Self {
+            model: "embedding-2".to_string(),
+            dimensions: Default::default(),
+            batch_size: 64,
+            api_key: Default::default(),
+            client: Default::default(),
+        }
+    }
+}
+
+impl GLMEmbeddingClient {
+    /// create_embeddings embeds all inputs with a single request
+    pub async fn create_embeddings(&self, input: Vec<String>) -> Result<Vec<Vec<f32>>, LlmError> {
+        let builder = glm_request_builder(&self.client, &self.api_key, "/embeddings")?;
+        let request = EmbeddingRequest {
+            model: self.model.clone(),
+            input,
+            dimensions: self.dimensions,
+            ..Default::default()
         };
+        create_embeddings(builder, &request).await
+    }
+}
+
+#[async_trait::async_trait]
+impl Embedding for GLMEmbeddingClient {
+    fn name(&self) -> &'static str {
+        "ChatGLM Text Embedding"
+    }
 
-        let res = model.invoke(InvokeMeta { prompt:invoke_prompt, invoke_param: self.invoke_param.clone() }).await;
-        Generation {
-            text: res["data"]["choices"].as_array().unwrap().into_iter().map(|x| Message{role: x["role"].to_string(), content: {
-                let content = x["content"].as_str().unwrap();
-                unescape(content).unwrap()
-            } }).collect(),
-            info: Some(
-                res["data"]["usage"].clone()
-            )
+    /// `dimensions` if set, otherwise the size of the model, 0 for an unknown model
+    fn dimension(&self) -> usize {
+        if let Some(dimensions) = self.dimensions {
+            return dimensions as usize;
+        }
+        match self.model.as_str() {
+            "embedding-2" => 1024,
+            "embedding-3" => 2048,
+            _ => 0,
         }
     }
+
+    async fn encode(&self, input: String) -> Vec<f32> {
+        self.encode_batch(vec![input]).await.remove(0)
+    }
+
+    async fn encode_batch(&self, inputs: Vec<String>) -> Vec<Vec<f32>> {
+        encode_in_chunks(inputs, self.batch_size, self.batch_concurrency(), |chunk| {
+            self.create_embeddings(chunk)
+        })
+        .await
+        .unwrap_or_else(|e| panic!("GLMEmbeddingClient: {}", e))
+    }
 }
 
 #[tokio::test]
@@ -89,16 +392,116 @@ async fn test_text_embedding() {
         sum.sqrt()
     };
 
-    let r1 = GLMEmbeddingClient{}.encode("你好吗?".to_string()).await;
-    let r2 = GLMEmbeddingClient{}.encode("how are you?".to_string()).await;
+    let r1 = GLMEmbeddingClient::default().encode("你好吗?".to_string()).await;
+    let r2 = GLMEmbeddingClient::default().encode("how are you?".to_string()).await;
     // calculate distance between r1 r2 sentences
     let sum12 = distance_fn(&r1, &r2);
     println!("distance r1 r2: {}", sum12);
 
-    let r3 = GLMEmbeddingClient{}.encode("今天是个艳阳天".to_string()).await;
+    let r3 = GLMEmbeddingClient::default().encode("今天是个艳阳天".to_string()).await;
     // calculate distance between r1 r3 sentences
     let sum13 = distance_fn(&r1, &r3);
     println!("distance r1 r3: {}", sum13);
 
     assert!(sum12 < sum13)
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_glm_stream() {
+    use std::str::FromStr;
+
+    use futures::StreamExt;
+
+    dotenvy::dotenv().unwrap();
+    let mut stream = GLMClient::default()
+        .generate_stream(
+            vec![Message::from_str("user: 从1数到20").unwrap()],
+            vec![],
+            Default::default(),
+        )
+        .await;
+    let mut answer = String::new();
+    while let Some(delta) = stream.next().await {
+        let delta = delta.unwrap();
+        print!("{}", delta.text[0].content);
+        answer += &delta.text[0].content.to_string();
+    }
+    assert!(!answer.is_empty())
+}
+
+#[test]
+fn test_glm_image_message() {
+    let message = Message {
+        role: Role::User,
+        content: vec![
+            ContentPart::Text {
+                text: "这是什么?".to_string(),
+            },
+            ContentPart::ImageUrl {
+                url: "https://example.com/cat.png".to_string(),
+            },
+        ]
+        .into(),
+        ..Default::default()
+    };
+    assert!(matches!(
+        message_to_glm(&message, false),
+        Err(LlmError::InvalidRequest(_))
+    ));
+    let prompt = message_to_glm(&message, true).unwrap();
+    assert_eq!(prompt.content.unwrap()[1]["image_url"]["url"], "https://example.com/cat.png");
+}
+
+#[test]
+fn test_glm_request() {
+    use base64::Engine;
+
+    let client = GLMClient::new("glm-4-air").as_character(CharacterGLMMeta {
+        bot_name: "Ashly".to_string(),
+        ..Default::default()
+    });
+    let request = client
+        .create_request(
+            &[Message { role: Role::User, content: "hi".into(), ..Default::default() }],
+            &["\n\n".to_string(), "END".to_string()],
+            GenerateOptions {
+                temperature: Some(0.1),
+                response_format: Some(ResponseFormat::JsonObject),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let request = serde_json::to_value(request).unwrap();
+    assert_eq!(request["model"], "charglm-3");
+    assert_eq!(request["stop"], json!(["\n\n"]));
+    assert_eq!(request["meta"]["bot_name"], "Ashly");
+    assert_eq!(request["response_format"]["type"], "json_object");
+    let hot = GenerateOptions {
+        temperature: Some(1.5),
+        ..Default::default()
+    };
+    assert!(matches!(client.create_request(&[], &[], hot), Err(LlmError::InvalidRequest(_))));
+    let cold = GenerateOptions {
+        temperature: Some(0.0),
+        ..Default::default()
+    };
+    let request = serde_json::to_value(client.create_request(&[], &[], cold).unwrap()).unwrap();
+    assert_eq!(request["do_sample"], false);
+    assert!(request.get("temperature").is_none());
+    for top_p in [0.0, 1.0] {
+        let options = GenerateOptions {
+            top_p: Some(top_p),
+            ..Default::default()
+        };
+        assert!(matches!(client.create_request(&[], &[], options), Err(LlmError::InvalidRequest(_))));
+    }
+    assert_eq!(truncate_at_stop("hello END world", &["END".to_string()]).unwrap(), "hello ");
+
+    let token = generate_token("my-id.my-secret").unwrap();
+    let header = token.split('.').next().unwrap();
+    let header = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(header).unwrap();
+    let header: serde_json::Value = serde_json::from_slice(&header).unwrap();
+    assert_eq!(header["sign_type"], "SIGN");
+    assert!(matches!(glm_error("1113", "arrears"), Some(LlmError::QuotaExceeded(_))));
+    assert!(glm_error("invalid_api_key", "").is_none());
+}
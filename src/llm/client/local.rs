@@ -0,0 +1,177 @@
+use std::{collections::BTreeMap, sync::OnceLock};
+
+use http::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use crate::llm::{GenerateOptions, GenerationStream, LlmError, LLM};
+use crate::schema::{Generation, Message};
+
+use super::openai_api::{
+    apply_options, create_chat_completion, create_chat_completion_stream, ChatCompletionRequest, ChatMessage,
+};
+
+/// LocalClient talks to a self-hosted server speaking the OpenAI chat completions api,
+/// e.g. llama.cpp server, Ollama or vLLM. no auth is sent unless `headers` is set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LocalClient {
+    /// base url including the version prefix, e.g. `http://localhost:11434/v1`
+    pub api_base: String,
+    /// whatever name the server knows the model by, e.g. `llama3:8b`
+    pub model: String,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub n: Option<u8>,
+    pub max_tokens: Option<u16>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+
+    /// extra headers sent with every request, e.g. `Authorization: Bearer ...` or `X-Api-Key`
+    #[serde(skip)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub(crate) client: OnceLock<reqwest::Client>,
+}
+
+impl Default for LocalClient {
+    fn default() -> Self {
+        Self {
+            api_base: std::env::var("LOCAL_LLM_API_BASE")
+                .unwrap_or_else(|_| "http://localhost:8080/v1".to_string()),
+            model: std::env::var("LOCAL_LLM_MODEL").unwrap_or_else(|_| "default".to_string()),
+            temperature: Default::default(),
+            top_p: Default::default(),
+            n: Default::default(),
+            max_tokens: Default::default(),
+            presence_penalty: Default::default(),
+            frequency_penalty: Default::default(),
+            headers: Default::default(),
+            client: Default::default(),
+        }
+    }
+}
+
+impl LocalClient {
+    pub fn new(api_base: impl Into<String>, model: impl Into<String>) -> Self {
+        Self {
+            api_base: api_base.into(),
+            model: model.into(),
+            ..Default::default()
+        }
+    }
+
+    fn request_builder(&self) -> Result<reqwest::RequestBuilder, LlmError> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| LlmError::InvalidRequest(format!("header {}: {}", name, e)))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| LlmError::InvalidRequest(format!("header {}: {}", name, e)))?;
+            headers.insert(name, value);
+        }
+        Ok(self
+            .client
+            .get_or_init(reqwest::Client::new)
+            .post(format!("{}/chat/completions", self.api_base.trim_end_matches('/')))
+            .headers(headers))
+    }
+
+    fn create_request(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<ChatCompletionRequest, LlmError> {
+        let request = ChatCompletionRequest {
+            model: self.model.clone(),
+            messages: input.into_iter().map(ChatMessage::from).collect(),
+            stop,
+            n: self.n,
+            max_tokens: self.max_tokens,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            ..Default::default()
+        };
+        apply_options(request, options)
+    }
+}
+
+#[async_trait::async_trait]
+impl LLM for LocalClient {
+    fn name(&self) -> &'static str {
+        "Local"
+    }
+
+    async fn generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        create_chat_completion(self.request_builder()?, &self.create_request(input, stop, options)?).await
+    }
+
+    async fn generate_stream(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> GenerationStream {
+        let request = self
+            .request_builder()
+            .and_then(|builder| Ok((builder, self.create_request(input, stop, options)?)));
+        match request {
+            Ok((builder, request)) => create_chat_completion_stream(builder, request),
+            Err(e) => Box::pin(futures::stream::once(async move { Err(e) })),
+        }
+    }
+}
+
+#[test]
+fn test_local_client() {
+    use std::str::FromStr;
+
+    let client = LocalClient {
+        temperature: Some(0.3),
+        ..LocalClient::new("http://localhost:11434/v1/", "llama3")
+    };
+    let request = client.request_builder().unwrap().build().unwrap();
+    assert_eq!(request.url().as_str(), "http://localhost:11434/v1/chat/completions");
+    assert!(request.headers().get("authorization").is_none());
+
+    let chat = client
+        .create_request(
+            vec![Message::from_str("USER: say hello").unwrap()],
+            vec![],
+            GenerateOptions {
+                max_tokens: Some(8),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(chat.model, "llama3");
+    assert_eq!(chat.temperature, Some(0.3));
+    assert_eq!(chat.max_tokens, Some(8));
+
+    // text is the default, it is not sent to servers that may reject response_format
+    let chat = client
+        .create_request(
+            vec![],
+            vec![],
+            GenerateOptions {
+                response_format: Some(crate::llm::ResponseFormat::Text),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(chat.response_format, None);
+
+    let mut client = client;
+    client.headers.insert("X-Api-Key".to_string(), "secret".to_string());
+    let request = client.request_builder().unwrap().build().unwrap();
+    assert_eq!(request.headers()["x-api-key"], "secret");
+    client.headers.insert("bad header".to_string(), "x".to_string());
+    assert!(matches!(client.request_builder(), Err(LlmError::InvalidRequest(_))));
+}
@@ -1,2 +1,4 @@
 pub mod glm;
+pub mod local;
 pub mod openai;
+pub(crate) mod openai_api;
@@ -1,17 +1,21 @@
 use std::{collections::BTreeMap, sync::OnceLock};
 
-use async_openai::{
-    config::{Config, OPENAI_API_BASE, OPENAI_ORGANIZATION_HEADER},
-    types::{ChatCompletionRequestMessage, CreateChatCompletionRequest},
-};
+use async_openai::config::{Config, OPENAI_API_BASE, OPENAI_ORGANIZATION_HEADER};
+use futures::StreamExt;
 use http::{header::AUTHORIZATION, HeaderMap};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
 use crate::
-    schema::{Generation, Message};
+    schema::{tool::{Tool, ToolChoice}, usage::PriceTable, Generation, Message};
+
+use crate::llm::{Embedding, GenerateOptions, GenerationStream, LlmError, LLM};
 
-use crate::llm::LLM;
+use super::openai_api::{
+    apply_options, create_chat_completion, create_chat_completion_stream, create_embeddings, encode_in_chunks,
+    tool_choice_to_json,
+    tool_to_json, ChatCompletionRequest, ChatMessage, EmbeddingRequest,
+};
 
 /// Configuration for OpenAI API
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -68,6 +72,7 @@ impl Config for OpenAIConfig {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct OpenAIClient {
     pub model: String,
     pub temperature: Option<f32>, // min: 0, max: 2, default: 1,
@@ -77,11 +82,36 @@ pub struct OpenAIClient {
     pub presence_penalty: Option<f32>, // min: -2.0, max: 2.0, default 0
     pub frequency_penalty: Option<f32>, // min: -2.0, max: 2.0, default: 0
     pub user: Option<String>,
+    /// tools the model may call
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<Tool>,
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoice>,
 
-    #[serde(skip)]
+    /// only the api base is serialized, so cache and cassette keys tell servers apart,
+    /// the key never leaves the client and is read from the environment on load
+    #[serde(
+        rename = "api_base",
+        serialize_with = "serialize_api_base",
+        deserialize_with = "deserialize_api_base"
+    )]
     pub config: OpenAIConfig,
+    /// prices used to fill in the cost of every call
+    #[serde(skip)]
+    pub prices: PriceTable,
     #[serde(skip)]
-    pub(crate) client: OnceLock<async_openai::Client<OpenAIConfig>>,
+    pub(crate) client: OnceLock<reqwest::Client>,
+}
+
+fn serialize_api_base<S: serde::Serializer>(config: &OpenAIConfig, serializer: S) -> Result<S::Ok, S::Error> {
+    config.api_base.serialize(serializer)
+}
+
+fn deserialize_api_base<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<OpenAIConfig, D::Error> {
+    Ok(OpenAIConfig {
+        api_base: String::deserialize(deserializer)?,
+        ..Default::default()
+    })
 }
 
 impl Default for OpenAIClient {
@@ -96,6 +126,9 @@ impl Default for OpenAIClient {
             presence_penalty: Default::default(),
             frequency_penalty: Default::default(),
             user: Default::default(),
+            tools: Default::default(),
+            tool_choice: Default::default(),
+            prices: Default::default(),
             client: Default::default(),
         }
     }
@@ -111,16 +144,40 @@ impl Eq for OpenAIClient {}
 
 // ====== LLM ======
 
-fn message_to_chat_completion_request_message(message: Message) -> ChatCompletionRequestMessage {
-    ChatCompletionRequestMessage {
-        role: match message.role.as_str().to_lowercase().as_str() {
-            "user" => async_openai::types::Role::User,
-            "assistant" => async_openai::types::Role::Assistant,
-            "system" => async_openai::types::Role::System,
-            _ => panic!("Invalid role"),
-        },
-        content: Some(message.content),
-        ..Default::default()
+impl OpenAIClient {
+    fn create_request(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<ChatCompletionRequest, LlmError> {
+        let request = ChatCompletionRequest {
+            model: self.model.clone(),
+            messages: input.into_iter().map(ChatMessage::from).collect(),
+            stop,
+            n: self.n,
+            max_tokens: self.max_tokens,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            user: self.user.clone(),
+            tools: self.tools.iter().map(tool_to_json).collect(),
+            tool_choice: self.tool_choice.as_ref().map(tool_choice_to_json),
+            ..Default::default()
+        };
+        apply_options(request, options)
+    }
+
+    async fn create_chat_completion(&self, request: ChatCompletionRequest) -> Result<Generation, LlmError> {
+        let builder = self
+            .client
+            .get_or_init(reqwest::Client::new)
+            .post(self.config.url("/chat/completions"))
+            .headers(self.config.headers());
+        let mut generation = create_chat_completion(builder, &request).await?;
+        generation.usage = generation.usage.map(|u| u.priced(&self.prices, &self.model));
+        Ok(generation)
     }
 }
 
@@ -130,49 +187,129 @@ impl LLM for OpenAIClient {
         "OpenAI"
     }
 
-    async fn generate(&self, input: Vec<Message>, stop: Vec<String>) -> Generation {
-        let client = self
+    async fn generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        self.create_chat_completion(self.create_request(input, stop, options)?).await
+    }
+
+    async fn generate_stream(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> GenerationStream {
+        let mut request = match self.create_request(input, stop, options) {
+            Ok(request) => request,
+            Err(e) => return Box::pin(futures::stream::once(async move { Err(e) })),
+        };
+        // openai only reports the usage of a stream, in a last chunk without choices, when asked to
+        request.extra.insert(
+            "stream_options".to_string(),
+            serde_json::json!({ "include_usage": true }),
+        );
+        let builder = self
             .client
-            .get_or_init(|| async_openai::Client::with_config(self.config.clone()));
-        let res = client
-            .chat()
-            .create(CreateChatCompletionRequest {
-                model: self.model.clone(),
-                messages: input
-                    .into_iter()
-                    .map(message_to_chat_completion_request_message)
-                    .collect(),
-                stop: Some(async_openai::types::Stop::StringArray(stop)),
-                n: self.n,
-                max_tokens: self.max_tokens,
-                temperature: self.temperature,
-                top_p: self.top_p,
-                presence_penalty: self.presence_penalty,
-                frequency_penalty: self.frequency_penalty,
-                user: self.user.clone(),
-                ..Default::default()
-            })
-            .await
-            .unwrap();
-
-        Generation {
-            text: res
-                .choices
-                .iter()
-                .map(|choice| Message {
-                    role: choice.message.role.clone().to_string(),
-                    content: choice.message.content.clone().unwrap(),
-                })
-                .collect(),
-            info: {
-                if let Some(usage) = res.usage {
-                    serde_json::to_value(usage).ok()
-                } else {
-                    None
-                }
-            },
+            .get_or_init(reqwest::Client::new)
+            .post(self.config.url("/chat/completions"))
+            .headers(self.config.headers());
+        let (prices, model) = (self.prices.clone(), self.model.clone());
+        Box::pin(
+            create_chat_completion_stream(builder, request).map(
+                move |delta| {
+                    delta.map(|mut delta| {
+                        delta.usage = delta.usage.map(|u| u.priced(&prices, &model));
+                        delta
+                    })
+                },
+            ),
+        )
+    }
+}
+
+// ====== Embedding ======
+
+/// OpenAIEmbeddingClient embeds texts with the OpenAI embeddings api,
+/// point `config.api_base` to an OpenAI compatible server to use a local model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpenAIEmbeddingClient {
+    pub model: String,
+    /// shortens the vectors, only supported by `text-embedding-3-*` and later models
+    pub dimensions: Option<u32>,
+    pub user: Option<String>,
+    /// how many texts are sent in one request, openai accepts up to 2048
+    pub batch_size: usize,
+
+    #[serde(skip)]
+    pub config: OpenAIConfig,
+    #[serde(skip)]
+    pub(crate) client: OnceLock<reqwest::Client>,
+}
+
+impl Default for OpenAIEmbeddingClient {
+    fn default() -> Self {
+        Self {
+            model: "text-embedding-3-small".to_string(),
+            dimensions: Default::default(),
+            user: Default::default(),
+            batch_size: 2048,
+            config: Default::default(),
+            client: Default::default(),
+        }
+    }
+}
+
+impl OpenAIEmbeddingClient {
+    /// create_embeddings embeds all inputs with a single request
+    pub async fn create_embeddings(&self, input: Vec<String>) -> Result<Vec<Vec<f32>>, LlmError> {
+        let builder = self
+            .client
+            .get_or_init(reqwest::Client::new)
+            .post(self.config.url("/embeddings"))
+            .headers(self.config.headers());
+        let request = EmbeddingRequest {
+            model: self.model.clone(),
+            input,
+            dimensions: self.dimensions,
+            user: self.user.clone(),
+        };
+        create_embeddings(builder, &request).await
+    }
+}
+
+#[async_trait::async_trait]
+impl Embedding for OpenAIEmbeddingClient {
+    fn name(&self) -> &'static str {
+        "OpenAI Embedding"
+    }
+
+    /// 0 when the model is unknown and `dimensions` is not set
+    fn dimension(&self) -> usize {
+        if let Some(dimensions) = self.dimensions {
+            return dimensions as usize;
+        }
+        match self.model.as_str() {
+            "text-embedding-3-large" => 3072,
+            "text-embedding-3-small" | "text-embedding-ada-002" => 1536,
+            _ => 0,
         }
     }
+
+    async fn encode(&self, input: String) -> Vec<f32> {
+        self.encode_batch(vec![input]).await.remove(0)
+    }
+
+    async fn encode_batch(&self, inputs: Vec<String>) -> Vec<Vec<f32>> {
+        encode_in_chunks(inputs, self.batch_size, self.batch_concurrency(), |chunk| {
+            self.create_embeddings(chunk)
+        })
+        .await
+        .unwrap_or_else(|e| panic!("OpenAIEmbeddingClient: {}", e))
+    }
 }
 
 #[tokio::test]
@@ -191,7 +328,83 @@ async fn test_openai_client() {
                 Message::from_str("USER: write something about comparing rust and go").unwrap(),
             ],
             vec!["stop".to_string()],
+            Default::default(),
         )
         .await;
     println!("{:#?}", res)
 }
+
+
+#[tokio::test]
+async fn test_openai_client_stream() {
+    use std::str::FromStr;
+
+    dotenvy::dotenv().unwrap();
+    let client = OpenAIClient::default();
+    let mut stream = client
+        .generate_stream(
+            vec![
+                Message::from_str("SYSTEM: you are a helpful assistant").unwrap(),
+                Message::from_str("USER: count from 1 to 20").unwrap(),
+            ],
+            vec!["stop".to_string()],
+            Default::default(),
+        )
+        .await;
+    let mut answer = String::new();
+    let mut usage = None;
+    while let Some(delta) = stream.next().await {
+        let delta = delta.unwrap();
+        usage = usage.or(delta.usage);
+        if let Some(message) = delta.text.first() {
+            print!("{}", message.content);
+            answer += &message.content.to_string();
+        }
+    }
+    assert!(!answer.is_empty());
+    assert!(usage.unwrap().completion_tokens > 0);
+}
+
+#[tokio::test]
+async fn test_openai_tool_call() {
+    use std::str::FromStr;
+
+    dotenvy::dotenv().unwrap();
+    let client = OpenAIClient {
+        tools: vec![Tool {
+            name: "get_weather".to_string(),
+            description: "get the current weather of a city".to_string(),
+            parameters: serde_json::json!({
+                "type": "object",
+                "properties": {"city": {"type": "string"}},
+                "required": ["city"],
+            }),
+        }],
+        tool_choice: Some(ToolChoice::Auto),
+        ..Default::default()
+    };
+    let res = client
+        .generate(
+            vec![Message::from_str("USER: what is the weather in Paris?").unwrap()],
+            vec![],
+            Default::default(),
+        )
+        .await
+        .unwrap();
+    println!("{:#?}", res);
+    assert_eq!(res.text[0].tool_calls[0].name, "get_weather");
+}
+
+#[tokio::test]
+async fn test_openai_embedding() {
+    dotenvy::dotenv().unwrap();
+    let client = OpenAIEmbeddingClient {
+        dimensions: Some(256),
+        ..Default::default()
+    };
+    let vectors = client
+        .encode_batch(vec!["你好吗?".to_string(), "how are you?".to_string()])
+        .await;
+    assert_eq!(vectors.len(), 2);
+    assert!(vectors.iter().all(|v| v.len() == client.dimension()));
+}
@@ -0,0 +1,749 @@
+//! wire format of the OpenAI chat completions and embeddings api, shared by every OpenAI style client
+
+use std::{future::Future, time::Duration};
+
+use futures::{StreamExt, TryStreamExt};
+use reqwest_eventsource::{Event, EventSource};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::glm::glm_error;
+
+use crate::llm::{
+    error::{retry_after_header, retry_after_message},
+    GenerateOptions, GenerationStream, LlmError, ResponseFormat,
+};
+use crate::schema::{
+    content::{Content, ContentPart},
+    logprob::TokenLogprob,
+    tool::{Tool, ToolCall, ToolChoice},
+    usage::Usage,
+    Generation, Message, Role,
+};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct ChatCompletionRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u8>,
+    /// time limit of the whole call, for a stream until its last delta
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<serde_json::Value>,
+    /// `{"type": "json_object"}` for json mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<serde_json::Value>,
+    /// provider specific fields, e.g. the character `meta` of GLM
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ChatMessage {
+    /// some self-hosted servers leave out the role of the answer
+    #[serde(default)]
+    pub role: String,
+    /// a string, or a list of typed parts for vision models
+    #[serde(default)]
+    pub content: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ChatToolCall>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ChatToolCall {
+    #[serde(default)]
+    pub id: String,
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: ChatFunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ChatFunctionCall {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub arguments: String,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ChatCompletionResponse {
+    pub choices: Vec<ChatChoice>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ChatChoice {
+    #[serde(default)]
+    pub message: Option<ChatMessage>,
+    /// plain completion text, returned instead of `message` by some self-hosted servers
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub logprobs: Option<ChatLogprobs>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct ChatLogprobs {
+    #[serde(default)]
+    pub content: Option<Vec<TokenLogprob>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ChatCompletionChunk {
+    #[serde(default)]
+    pub choices: Vec<ChatChunkChoice>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ChatChunkChoice {
+    #[serde(default)]
+    pub index: usize,
+    #[serde(default)]
+    pub delta: ChatDelta,
+    #[serde(default)]
+    pub logprobs: Option<ChatLogprobs>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct ChatDelta {
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Option<Vec<ChatToolCallDelta>>,
+}
+
+/// a piece of a tool call, the pieces of one call share the same `index`
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ChatToolCallDelta {
+    #[serde(default)]
+    pub index: usize,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub function: Option<ChatFunctionDelta>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ChatFunctionDelta {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub arguments: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct EmbeddingRequest {
+    pub model: String,
+    pub input: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct EmbeddingResponse {
+    pub data: Vec<EmbeddingData>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct EmbeddingData {
+    #[serde(default)]
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+pub(crate) fn response_format_to_json(format: &ResponseFormat) -> Option<serde_json::Value> {
+    match format {
+        // text is the default of every server, leaving it out keeps servers that reject the field working
+        ResponseFormat::Text => None,
+        ResponseFormat::JsonObject => Some(json!({"type": "json_object"})),
+        ResponseFormat::JsonSchema { name, schema } => Some(json!({
+            "type": "json_schema",
+            "json_schema": {"name": name, "schema": schema, "strict": true},
+        })),
+    }
+}
+
+/// apply_options validates the options of a call, the ones that are set override the request
+pub(crate) fn apply_options(
+    request: ChatCompletionRequest,
+    options: GenerateOptions,
+) -> Result<ChatCompletionRequest, LlmError> {
+    options.validate()?;
+    Ok(ChatCompletionRequest {
+        temperature: options.temperature.or(request.temperature),
+        top_p: options.top_p.or(request.top_p),
+        max_tokens: options.max_tokens.or(request.max_tokens),
+        n: options.n.or(request.n),
+        seed: options.seed.or(request.seed),
+        logprobs: options
+            .logprobs
+            .or(options.top_logprobs.map(|_| true))
+            .or(request.logprobs),
+        top_logprobs: options.top_logprobs.or(request.top_logprobs),
+        timeout: options.timeout.or(request.timeout),
+        presence_penalty: options.presence_penalty.or(request.presence_penalty),
+        frequency_penalty: options.frequency_penalty.or(request.frequency_penalty),
+        response_format: options
+            .response_format
+            .as_ref()
+            .map_or(request.response_format, response_format_to_json),
+        ..request
+    })
+}
+
+pub(crate) fn tool_to_json(tool: &Tool) -> serde_json::Value {
+    json!({
+        "type": "function",
+        "function": {
+            "name": tool.name,
+            "description": tool.description,
+            "parameters": tool.parameters,
+        }
+    })
+}
+
+pub(crate) fn tool_choice_to_json(choice: &ToolChoice) -> serde_json::Value {
+    match choice {
+        ToolChoice::None => json!("none"),
+        ToolChoice::Auto => json!("auto"),
+        ToolChoice::Required => json!("required"),
+        ToolChoice::Tool(name) => json!({"type": "function", "function": {"name": name}}),
+    }
+}
+
+/// role_to_openai maps a role to the openai role and the speaker name,
+/// custom roles speak as `user`, their name goes to the `name` field when openai accepts it
+/// (`^[a-zA-Z0-9_-]{1,64}$`) and is prefixed to the content otherwise
+fn role_to_openai(role: Role, content: Content) -> (String, Option<String>, Content) {
+    match role {
+        Role::Custom(name) => {
+            let valid_name = name.len() <= 64
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            if valid_name {
+                return ("user".to_string(), Some(name), content);
+            }
+            let content = match content {
+                Content::Text(text) => Content::Text(format!("{}: {}", name, text)),
+                Content::Parts(mut parts) => {
+                    parts.insert(0, ContentPart::Text { text: format!("{}:", name) });
+                    Content::Parts(parts)
+                }
+            };
+            ("user".to_string(), None, content)
+        }
+        role => (role.to_string(), None, content),
+    }
+}
+
+fn content_to_openai(content: Content) -> serde_json::Value {
+    match content {
+        Content::Text(text) => json!(text),
+        Content::Parts(parts) => json!(parts
+            .into_iter()
+            .map(|part| match part {
+                ContentPart::Text { text } => json!({"type": "text", "text": text}),
+                ContentPart::ImageUrl { url } => {
+                    json!({"type": "image_url", "image_url": {"url": url}})
+                }
+                ContentPart::ImageBase64 { media_type, data } => json!({
+                    "type": "image_url",
+                    "image_url": {"url": format!("data:{};base64,{}", media_type, data)},
+                }),
+            })
+            .collect::<Vec<_>>()),
+    }
+}
+
+fn content_from_openai(content: Option<serde_json::Value>) -> Content {
+    match content {
+        Some(serde_json::Value::String(text)) => Content::Text(text),
+        Some(serde_json::Value::Array(parts)) => Content::Parts(
+            parts
+                .iter()
+                .filter_map(|part| match part["type"].as_str()? {
+                    "text" => Some(ContentPart::Text {
+                        text: part["text"].as_str()?.to_string(),
+                    }),
+                    "image_url" => Some(ContentPart::ImageUrl {
+                        url: part["image_url"]["url"].as_str()?.to_string(),
+                    }),
+                    _ => None,
+                })
+                .collect(),
+        ),
+        _ => Content::default(),
+    }
+}
+
+impl From<Message> for ChatMessage {
+    fn from(message: Message) -> Self {
+        let (role, name, content) = role_to_openai(message.role, message.content);
+        let tool_calls = (!message.tool_calls.is_empty()).then(|| {
+            message
+                .tool_calls
+                .into_iter()
+                .map(|call| ChatToolCall {
+                    id: call.id,
+                    kind: function_type(),
+                    function: ChatFunctionCall {
+                        name: call.name,
+                        arguments: call.arguments,
+                    },
+                })
+                .collect()
+        });
+        Self {
+            role,
+            // an assistant message that only calls tools has no content
+            content: (tool_calls.is_none() || !content.is_empty()).then(|| content_to_openai(content)),
+            name,
+            tool_calls,
+            tool_call_id: message.tool_call_id,
+        }
+    }
+}
+
+impl From<ChatMessage> for Message {
+    fn from(message: ChatMessage) -> Self {
+        Self {
+            role: match message.role.as_str() {
+                "" => Role::Assistant,
+                role => role.into(),
+            },
+            content: content_from_openai(message.content),
+            tool_calls: message
+                .tool_calls
+                .unwrap_or_default()
+                .into_iter()
+                .map(|call| ToolCall {
+                    id: call.id,
+                    name: call.function.name,
+                    arguments: call.function.arguments,
+                })
+                .collect(),
+            tool_call_id: message.tool_call_id,
+        }
+    }
+}
+
+impl From<ChatCompletionResponse> for Generation {
+    fn from(res: ChatCompletionResponse) -> Self {
+        let (text, logprobs): (Vec<Message>, Vec<Vec<TokenLogprob>>) = res
+            .choices
+            .into_iter()
+            .map(|choice| {
+                let message = match choice.message {
+                    Some(message) => message.into(),
+                    None => Message {
+                        role: Role::Assistant,
+                        content: choice.text.unwrap_or_default().into(),
+                        ..Default::default()
+                    },
+                };
+                (message, choice.logprobs.and_then(|l| l.content).unwrap_or_default())
+            })
+            .unzip();
+        Generation {
+            text,
+            usage: res.usage,
+            info: None,
+            logprobs: if logprobs.iter().all(Vec::is_empty) { vec![] } else { logprobs },
+        }
+    }
+}
+
+/// every delta is put at its choice index, and every tool call piece at its call index
+impl From<ChatCompletionChunk> for Generation {
+    fn from(chunk: ChatCompletionChunk) -> Self {
+        let mut text: Vec<Message> = Vec::new();
+        let mut logprobs: Vec<Vec<TokenLogprob>> = Vec::new();
+        for choice in chunk.choices {
+            if text.len() <= choice.index {
+                text.resize(
+                    choice.index + 1,
+                    Message {
+                        role: Role::Assistant,
+                        ..Default::default()
+                    },
+                );
+            }
+            if let Some(tokens) = choice.logprobs.and_then(|l| l.content) {
+                if logprobs.len() <= choice.index {
+                    logprobs.resize(choice.index + 1, vec![]);
+                }
+                logprobs[choice.index] = tokens;
+            }
+            let mut tool_calls: Vec<ToolCall> = Vec::new();
+            for call in choice.delta.tool_calls.unwrap_or_default() {
+                if tool_calls.len() <= call.index {
+                    tool_calls.resize(call.index + 1, ToolCall::default());
+                }
+                let function = call.function.as_ref();
+                tool_calls[call.index] = ToolCall {
+                    id: call.id.unwrap_or_default(),
+                    name: function.and_then(|f| f.name.clone()).unwrap_or_default(),
+                    arguments: function.and_then(|f| f.arguments.clone()).unwrap_or_default(),
+                };
+            }
+            text[choice.index] = Message {
+                role: choice.delta.role.map_or(Role::Assistant, Role::from),
+                content: choice.delta.content.unwrap_or_default().into(),
+                tool_calls,
+                ..Default::default()
+            };
+        }
+        Generation {
+            text,
+            usage: chunk.usage,
+            info: None,
+            logprobs,
+        }
+    }
+}
+
+/// response_to_error classifies a failed response by its status and the error code in the body
+async fn response_to_error(res: reqwest::Response) -> LlmError {
+    let status = res.status().as_u16();
+    let header_retry_after = retry_after_header(res.headers());
+    let body = match res.text().await {
+        Ok(body) => body,
+        Err(e) => return e.into(),
+    };
+    let error = serde_json::from_str::<serde_json::Value>(&body)
+        .map(|json| json["error"].clone())
+        .unwrap_or_default();
+    let message = error["message"]
+        .as_str()
+        .map_or_else(|| body.clone(), str::to_string);
+    // GLM answers with numeric codes
+    if let Some(e) = error["code"].as_str().and_then(|code| glm_error(code, &message)) {
+        return e;
+    }
+    match error["code"].as_str() {
+        Some("context_length_exceeded") => LlmError::ContextLengthExceeded(message),
+        Some("insufficient_quota") => LlmError::QuotaExceeded(message),
+        Some("invalid_api_key") => LlmError::Auth(message),
+        _ => {
+            let retry_after = header_retry_after.or_else(|| retry_after_message(&message));
+            LlmError::from_status(status, message, retry_after)
+        }
+    }
+}
+
+/// response_to_generation fails a response without choices, chains expect at least one answer
+fn response_to_generation(res: ChatCompletionResponse) -> Result<Generation, LlmError> {
+    if res.choices.is_empty() {
+        return Err(LlmError::MalformedResponse("no choices in the response".to_string()));
+    }
+    Ok(res.into())
+}
+
+/// create_chat_completion sends the request with a builder that already targets the
+/// chat completions endpoint and carries the auth headers
+pub(crate) async fn create_chat_completion(
+    builder: reqwest::RequestBuilder,
+    request: &ChatCompletionRequest,
+) -> Result<Generation, LlmError> {
+    let call = async {
+        let res = builder.json(request).send().await?;
+        if !res.status().is_success() {
+            return Err(response_to_error(res).await);
+        }
+        response_to_generation(res.json().await?)
+    };
+    match request.timeout {
+        Some(timeout) => tokio::time::timeout(timeout, call)
+            .await
+            .unwrap_or(Err(LlmError::Timeout(timeout))),
+        None => call.await,
+    }
+}
+
+/// create_chat_completion_stream is the streaming version of create_chat_completion
+pub(crate) fn create_chat_completion_stream(
+    builder: reqwest::RequestBuilder,
+    request: ChatCompletionRequest,
+) -> GenerationStream {
+    let request = ChatCompletionRequest {
+        stream: Some(true),
+        ..request
+    };
+    // a json body can always be cloned
+    let es = EventSource::new(builder.json(&request)).unwrap();
+    let timeout = request.timeout;
+    let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+
+    Box::pin(futures::stream::unfold(es, move |mut es| async move {
+        loop {
+            let next = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, es.next()).await,
+                None => Ok(es.next().await),
+            };
+            let Ok(next) = next else {
+                es.close();
+                return Some((Err(LlmError::Timeout(timeout.unwrap_or_default())), es));
+            };
+            match next? {
+                Ok(Event::Open) => continue,
+                Ok(Event::Message(message)) => {
+                    if message.data == "[DONE]" {
+                        es.close();
+                        return None;
+                    }
+                    match serde_json::from_str::<ChatCompletionChunk>(&message.data) {
+                        Ok(chunk) => return Some((Ok(chunk.into()), es)),
+                        Err(e) => {
+                            es.close();
+                            return Some((Err(LlmError::MalformedResponse(e.to_string())), es));
+                        }
+                    }
+                }
+                // servers that do not send [DONE] just close the connection
+                Err(reqwest_eventsource::Error::StreamEnded) => {
+                    es.close();
+                    return None;
+                }
+                Err(e) => {
+                    es.close();
+                    return Some((Err(e.into()), es));
+                }
+            }
+        }
+    }))
+}
+
+/// create_embeddings sends the request with a builder that already targets the
+/// embeddings endpoint and carries the auth headers, vectors are returned in input order
+pub(crate) async fn create_embeddings(
+    builder: reqwest::RequestBuilder,
+    request: &EmbeddingRequest,
+) -> Result<Vec<Vec<f32>>, LlmError> {
+    let res = builder.json(request).send().await?;
+    if !res.status().is_success() {
+        return Err(response_to_error(res).await);
+    }
+    let mut data = res.json::<EmbeddingResponse>().await?.data;
+    if data.len() != request.input.len() {
+        return Err(LlmError::MalformedResponse(format!(
+            "{} embeddings returned for {} inputs",
+            data.len(),
+            request.input.len()
+        )));
+    }
+    data.sort_by_key(|d| d.index);
+    Ok(data.into_iter().map(|d| d.embedding).collect())
+}
+
+/// encode_in_chunks embeds the inputs with requests of at most `batch_size` texts, `concurrency` of them
+/// at the same time, the vectors are returned in input order
+pub(crate) async fn encode_in_chunks<F, Fut>(
+    inputs: Vec<String>,
+    batch_size: usize,
+    concurrency: usize,
+    create_embeddings: F,
+) -> Result<Vec<Vec<f32>>, LlmError>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<Vec<f32>>, LlmError>>,
+{
+    let chunks = inputs
+        .chunks(batch_size.max(1))
+        .map(<[String]>::to_vec)
+        .collect::<Vec<_>>();
+    let vectors = futures::stream::iter(chunks)
+        .map(create_embeddings)
+        .buffered(concurrency.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok(vectors.into_iter().flatten().collect())
+}
+
+#[tokio::test]
+async fn test_encode_in_chunks() {
+    let inputs = ["a", "bb", "ccc", "dddd", "eeeee"].map(String::from).to_vec();
+    let sizes = std::sync::Mutex::new(Vec::new());
+    let vectors = encode_in_chunks(inputs.clone(), 2, 2, |chunk| {
+        sizes.lock().unwrap().push(chunk.len());
+        async move { Ok(chunk.iter().map(|text| vec![text.len() as f32]).collect()) }
+    })
+    .await
+    .unwrap();
+    assert_eq!(vectors, vec![vec![1.0], vec![2.0], vec![3.0], vec![4.0], vec![5.0]]);
+    assert_eq!(*sizes.lock().unwrap(), vec![2, 2, 1]);
+
+    let failed = encode_in_chunks(inputs, 2, 2, |_| async { Err(LlmError::Transport("down".to_string())) }).await;
+    assert!(matches!(failed, Err(LlmError::Transport(_))));
+}
+
+#[test]
+fn test_tool_call_round_trip() {
+    let res: ChatCompletionResponse = serde_json::from_str(
+        r#"{
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {"name": "get_weather", "arguments": "{\"city\": \"Paris\"}"}
+                    }]
+                },
+                "finish_reason": "tool_calls"
+            }],
+            "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}
+        }"#,
+    )
+    .unwrap();
+    let generation: Generation = res.into();
+    let call = &generation.text[0].tool_calls[0];
+    assert_eq!(call.name, "get_weather");
+    assert_eq!(
+        call.parse_arguments::<serde_json::Value>().unwrap()["city"],
+        "Paris"
+    );
+
+    let request = ChatMessage::from(generation.text[0].clone());
+    let request = serde_json::to_value(request).unwrap();
+    assert!(request.get("content").unwrap().is_null());
+    assert_eq!(request["tool_calls"][0]["function"]["name"], "get_weather");
+
+    let answer = ChatMessage::from(Message {
+        role: Role::Tool,
+        content: "sunny".into(),
+        tool_call_id: Some("call_1".to_string()),
+        ..Default::default()
+    });
+    let answer = serde_json::to_value(answer).unwrap();
+    assert_eq!(answer["tool_call_id"], "call_1");
+    assert_eq!(answer["content"], "sunny");
+}
+
+#[test]
+fn test_custom_role_mapping() {
+    let message = ChatMessage::from(Message {
+        role: Role::Custom("akarachan".to_string()),
+        content: "hi".into(),
+        ..Default::default()
+    });
+    assert_eq!(message.role, "user");
+    assert_eq!(message.name.as_deref(), Some("akarachan"));
+
+    let message = ChatMessage::from(Message {
+        role: Role::Custom("电棍".to_string()),
+        content: "hi".into(),
+        ..Default::default()
+    });
+    assert_eq!(message.role, "user");
+    assert_eq!(message.name, None);
+    assert_eq!(message.content, Some(json!("电棍: hi")));
+}
+
+#[test]
+fn test_image_content() {
+    let message = ChatMessage::from(Message {
+        role: Role::User,
+        content: vec![
+            ContentPart::Text {
+                text: "what is in the picture?".to_string(),
+            },
+            ContentPart::ImageBase64 {
+                media_type: "image/png".to_string(),
+                data: "iVBORw0KGgo=".to_string(),
+            },
+        ]
+        .into(),
+        ..Default::default()
+    });
+    assert_eq!(
+        message.content,
+        Some(json!([
+            {"type": "text", "text": "what is in the picture?"},
+            {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+        ]))
+    );
+}
+
+#[test]
+fn test_lenient_response() {
+    let res: ChatCompletionResponse = serde_json::from_str(
+        r#"{"choices": [{"index": 0, "message": {"content": "hello"}}, {"index": 1, "text": "world"}]}"#,
+    )
+    .unwrap();
+    let generation = Generation::from(res);
+    assert_eq!(generation.text[0].role, Role::Assistant);
+    assert_eq!(generation.text[0].content, "hello");
+    assert_eq!(generation.text[1].content, "world");
+    assert!(generation.usage.is_none());
+
+    let res: ChatCompletionResponse = serde_json::from_str(r#"{"choices": []}"#).unwrap();
+    assert!(matches!(response_to_generation(res), Err(LlmError::MalformedResponse(_))));
+
+    // partial usage reports of self-hosted servers are accepted
+    let res: ChatCompletionResponse = serde_json::from_str(
+        r#"{"choices": [{"index": 0, "text": "hi"}], "usage": {"prompt_tokens": 5}}"#,
+    )
+    .unwrap();
+    assert_eq!(res.usage.unwrap().completion_tokens, 0);
+
+    let chunk: ChatCompletionChunk =
+        serde_json::from_str(r#"{"choices": [{"index": 0, "finish_reason": "stop"}]}"#).unwrap();
+    assert_eq!(Generation::from(chunk).text[0].content, "");
+
+    let res: ChatCompletionResponse = serde_json::from_str(
+        r#"{"choices": [{"index": 0, "message": {"content": "Yes"}, "logprobs": {"content": [
+            {"token": "Yes", "logprob": -0.1, "bytes": [89, 101, 115],
+             "top_logprobs": [{"token": "Yes", "logprob": -0.1, "bytes": null}, {"token": "No", "logprob": -2.4}]}
+        ]}}]}"#,
+    )
+    .unwrap();
+    let generation = Generation::from(res);
+    assert_eq!(generation.logprobs[0][0].top_logprobs[1].token, "No");
+    assert!((generation.confidence().unwrap() - (-0.1f64).exp()).abs() < 1e-9);
+}
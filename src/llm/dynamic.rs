@@ -0,0 +1,138 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::schema::{Generation, Message};
+
+use super::{GenerateOptions, GenerationStream, LlmError, LLM};
+
+/// DynLLM is the object safe half of LLM, every LLM is a DynLLM.
+/// `Box<dyn DynLLM>` and `Arc<dyn DynLLM>` are LLMs again, so a provider picked at runtime
+/// can be handed to any chain. methods are prefixed to not clash with the LLM ones
+#[async_trait::async_trait]
+pub trait DynLLM: Send + Sync {
+    fn dyn_name(&self) -> &'static str;
+    /// dyn_params is the serialized llm, what `Serialize` would produce
+    fn dyn_params(&self) -> serde_json::Value;
+    async fn dyn_generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError>;
+    async fn dyn_generate_stream(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> GenerationStream;
+}
+
+#[async_trait::async_trait]
+impl<L: LLM> DynLLM for L {
+    fn dyn_name(&self) -> &'static str {
+        self.name()
+    }
+
+    fn dyn_params(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    async fn dyn_generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        self.generate(input, stop, options).await
+    }
+
+    async fn dyn_generate_stream(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> GenerationStream {
+        self.generate_stream(input, stop, options).await
+    }
+}
+
+impl Serialize for dyn DynLLM {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.dyn_params().serialize(serializer)
+    }
+}
+
+#[async_trait::async_trait]
+impl LLM for Box<dyn DynLLM> {
+    fn name(&self) -> &'static str {
+        self.as_ref().dyn_name()
+    }
+
+    async fn generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        self.as_ref().dyn_generate(input, stop, options).await
+    }
+
+    async fn generate_stream(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> GenerationStream {
+        self.as_ref().dyn_generate_stream(input, stop, options).await
+    }
+}
+
+#[async_trait::async_trait]
+impl LLM for Arc<dyn DynLLM> {
+    fn name(&self) -> &'static str {
+        self.as_ref().dyn_name()
+    }
+
+    async fn generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        self.as_ref().dyn_generate(input, stop, options).await
+    }
+
+    async fn generate_stream(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> GenerationStream {
+        self.as_ref().dyn_generate_stream(input, stop, options).await
+    }
+}
+
+#[tokio::test]
+async fn test_dyn_llm() {
+    use super::fake::FakeLLM;
+    use crate::btreemap;
+    use crate::chain::{llm_chain::LLMChain, Chain};
+    use crate::prompt_template::PromptTemplate;
+
+    let chain = LLMChain::new(Some(PromptTemplate::from("{question}".to_string())));
+    let input = btreemap! {
+        "question".to_string() => "What is human?".to_string()
+    };
+
+    let llm: Box<dyn DynLLM> = Box::new(FakeLLM::new(["a person"]));
+    let res = chain.apply(None, &llm, &input, vec![], Default::default()).await.unwrap().unwrap();
+    assert_eq!(res["answer"].content, "a person");
+    assert_eq!(llm.name(), "Fake");
+
+    let llm: Arc<dyn DynLLM> = Arc::new(FakeLLM::new(["a person"]));
+    let shared = llm.clone();
+    let res = chain.apply(None, &shared, &input, vec![], Default::default()).await.unwrap().unwrap();
+    assert_eq!(res["answer"].content, "a person");
+    assert_eq!(serde_json::to_value(&llm).unwrap(), serde_json::json!({}));
+}
@@ -0,0 +1,212 @@
+use std::{sync::OnceLock, time::Duration};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// LlmError tells apart the ways a provider call can fail, so callers can decide
+/// whether to retry, shrink the prompt, switch provider or give up
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum LlmError {
+    /// the api key is missing, invalid or has no permission
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    /// the provider asks to slow down, `retry_after` is the wait hint if the provider gave one
+    #[error("rate limited: {message}")]
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    /// the account has no balance or quota left
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
+    /// the prompt (plus max tokens) does not fit into the model context window
+    #[error("context length exceeded: {0}")]
+    ContextLengthExceeded(String),
+    /// the request is rejected by the client or the provider, e.g. unknown model
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    /// the provider answered something we cannot understand
+    #[error("malformed response: {0}")]
+    MalformedResponse(String),
+    /// the provider failed on its side
+    #[error("provider error ({status:?}): {message}")]
+    Provider {
+        status: Option<u16>,
+        message: String,
+    },
+    /// the request never got a complete answer, e.g. connection reset or dns failure
+    #[error("transport error: {0}")]
+    Transport(String),
+    /// no answer within the time limit
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+    /// the caller gave up on the request, e.g. the client disconnected
+    #[error("cancelled")]
+    Cancelled,
+    /// the conversation history could not be read from the memory
+    #[error("memory error: {0}")]
+    Memory(String),
+}
+
+/// ErrorClass is the kind of an LlmError without its details, used to configure
+/// which failures a combinator reacts to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    Auth,
+    RateLimited,
+    QuotaExceeded,
+    ContextLengthExceeded,
+    InvalidRequest,
+    MalformedResponse,
+    Provider,
+    Transport,
+    Timeout,
+    Cancelled,
+    Memory,
+}
+
+impl LlmError {
+    /// from_status classifies a failed http response by its status code
+    pub fn from_status(status: u16, message: String, retry_after: Option<Duration>) -> Self {
+        match status {
+            401 | 403 => Self::Auth(message),
+            429 => Self::RateLimited {
+                message,
+                retry_after,
+            },
+            400..=499 => Self::InvalidRequest(message),
+            _ => Self::Provider {
+                status: Some(status),
+                message,
+            },
+        }
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Self::Auth(_) => ErrorClass::Auth,
+            Self::RateLimited { .. } => ErrorClass::RateLimited,
+            Self::QuotaExceeded(_) => ErrorClass::QuotaExceeded,
+            Self::ContextLengthExceeded(_) => ErrorClass::ContextLengthExceeded,
+            Self::InvalidRequest(_) => ErrorClass::InvalidRequest,
+            Self::MalformedResponse(_) => ErrorClass::MalformedResponse,
+            Self::Provider { .. } => ErrorClass::Provider,
+            Self::Transport(_) => ErrorClass::Transport,
+            Self::Timeout(_) => ErrorClass::Timeout,
+            Self::Cancelled => ErrorClass::Cancelled,
+            Self::Memory(_) => ErrorClass::Memory,
+        }
+    }
+
+    /// is_transient reports whether the same request may succeed when sent again later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::RateLimited { .. } | Self::Transport(_) | Self::Timeout(_) => true,
+            Self::Provider { status, .. } => status.is_none_or(|s| s >= 500),
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for LlmError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            Self::MalformedResponse(e.to_string())
+        } else if let Some(status) = e.status() {
+            Self::from_status(status.as_u16(), e.to_string(), None)
+        } else {
+            Self::Transport(e.to_string())
+        }
+    }
+}
+
+impl From<reqwest_eventsource::Error> for LlmError {
+    fn from(e: reqwest_eventsource::Error) -> Self {
+        match e {
+            reqwest_eventsource::Error::Transport(e) => e.into(),
+            reqwest_eventsource::Error::InvalidStatusCode(status) => {
+                Self::from_status(status.as_u16(), status.to_string(), None)
+            }
+            reqwest_eventsource::Error::StreamEnded => Self::Transport(e.to_string()),
+            e => Self::MalformedResponse(e.to_string()),
+        }
+    }
+}
+
+/// retry_after_header reads the `Retry-After` header in its delay-seconds form
+pub(crate) fn retry_after_header(headers: &http::HeaderMap) -> Option<Duration> {
+    headers
+        .get(http::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+}
+
+/// retry_after_message reads wait hints written in the error message,
+/// e.g. `Please try again in 20s.`, `try again in 345ms` or `try again in 1m30s`
+pub(crate) fn retry_after_message(message: &str) -> Option<Duration> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    static PART_RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        Regex::new(r"(?i)try again in ((?:\d+(?:\.\d+)?(?:ms|h|m|s))+)").unwrap()
+    });
+    let part_re = PART_RE.get_or_init(|| Regex::new(r"(\d+(?:\.\d+)?)(ms|h|m|s)").unwrap());
+
+    let hint = re.captures(message)?.get(1)?.as_str();
+    let secs = part_re
+        .captures_iter(hint)
+        .map(|part| {
+            let value = part[1].parse::<f64>().unwrap_or_default();
+            match &part[2] {
+                "ms" => value / 1000.0,
+                "m" => value * 60.0,
+                "h" => value * 3600.0,
+                _ => value,
+            }
+        })
+        .sum::<f64>();
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+#[test]
+fn test_error_classification() {
+    let mut headers = http::HeaderMap::new();
+    headers.insert(http::header::RETRY_AFTER, "2".parse().unwrap());
+    let e = LlmError::from_status(429, "slow down".to_string(), retry_after_header(&headers));
+    assert!(matches!(
+        e,
+        LlmError::RateLimited { retry_after: Some(d), .. } if d == Duration::from_secs(2)
+    ));
+    assert!(e.is_transient());
+
+    assert!(matches!(LlmError::from_status(401, String::new(), None), LlmError::Auth(_)));
+    assert!(!LlmError::from_status(400, String::new(), None).is_transient());
+    assert!(LlmError::from_status(503, String::new(), None).is_transient());
+
+    // hints too large for a Duration are ignored instead of panicking
+    let mut headers = http::HeaderMap::new();
+    headers.insert(http::header::RETRY_AFTER, "99999999999999999999".parse().unwrap());
+    assert_eq!(retry_after_header(&headers), None);
+}
+
+#[test]
+fn test_retry_after_message() {
+    assert_eq!(
+        retry_after_message("Rate limit reached for gpt-4. Please try again in 20s."),
+        Some(Duration::from_secs(20))
+    );
+    assert_eq!(
+        retry_after_message("Please try again in 345ms."),
+        Some(Duration::from_millis(345))
+    );
+    assert_eq!(
+        retry_after_message("Please try again in 1m30s."),
+        Some(Duration::from_secs(90))
+    );
+    assert_eq!(retry_after_message("Too many requests"), None);
+    assert_eq!(retry_after_message("try again in 99999999999999999999h"), None);
+}
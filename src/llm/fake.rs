@@ -0,0 +1,197 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use itertools::Itertools;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::schema::{usage::Usage, Generation, Message, Role};
+
+use super::{GenerateOptions, LlmError, LLM};
+
+/// FakeResponse is what FakeLLM answers to a call
+#[derive(Debug, Clone)]
+pub enum FakeResponse {
+    /// a single assistant message
+    Text(String),
+    Generation(Generation),
+    Error(LlmError),
+}
+
+impl From<&str> for FakeResponse {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+impl From<String> for FakeResponse {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<Generation> for FakeResponse {
+    fn from(generation: Generation) -> Self {
+        Self::Generation(generation)
+    }
+}
+
+impl From<LlmError> for FakeResponse {
+    fn from(e: LlmError) -> Self {
+        Self::Error(e)
+    }
+}
+
+impl From<FakeResponse> for Result<Generation, LlmError> {
+    fn from(response: FakeResponse) -> Self {
+        match response {
+            FakeResponse::Text(text) => Ok(Generation {
+                text: vec![Message {
+                    role: Role::Assistant,
+                    content: text.into(),
+                    ..Default::default()
+                }],
+                usage: None,
+                info: None,
+                logprobs: vec![],
+            }),
+            FakeResponse::Generation(generation) => Ok(generation),
+            FakeResponse::Error(e) => Err(e),
+        }
+    }
+}
+
+/// FakeCall is a request received by FakeLLM
+#[derive(Debug, Clone)]
+pub struct FakeCall {
+    pub messages: Vec<Message>,
+    pub stop: Vec<String>,
+    pub options: GenerateOptions,
+}
+
+impl FakeCall {
+    /// prompt is the text of all messages joined with new lines, rules are matched against it
+    pub fn prompt(&self) -> String {
+        self.messages.iter().map(|m| m.content.to_string()).join("\n")
+    }
+}
+
+/// FakeLLM answers with scripted responses without touching the network, for testing chains.
+/// a call is answered by the first rule whose regex matches the prompt,
+/// otherwise by the next response of the sequence, it fails once the sequence is used up
+#[derive(Debug, Default, Serialize)]
+pub struct FakeLLM {
+    #[serde(skip)]
+    pub responses: Vec<FakeResponse>,
+    #[serde(skip)]
+    pub rules: Vec<(Regex, FakeResponse)>,
+    /// usage reported by every answer that does not carry its own
+    #[serde(skip)]
+    pub usage: Option<Usage>,
+    /// how long every call takes, calls with a shorter `GenerateOptions.timeout` time out
+    #[serde(skip)]
+    pub delay: Option<Duration>,
+    #[serde(skip)]
+    next: AtomicUsize,
+    #[serde(skip)]
+    calls: Mutex<Vec<FakeCall>>,
+}
+
+impl FakeLLM {
+    /// new creates a FakeLLM answering the given responses in sequence
+    pub fn new<R: Into<FakeResponse>>(responses: impl IntoIterator<Item = R>) -> Self {
+        Self {
+            responses: responses.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// on answers every prompt matching the regex with the response, panics on an invalid regex
+    pub fn on(mut self, pattern: &str, response: impl Into<FakeResponse>) -> Self {
+        self.rules.push((Regex::new(pattern).unwrap(), response.into()));
+        self
+    }
+
+    /// calls returns the received requests in the order they arrived
+    pub fn calls(&self) -> Vec<FakeCall> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl LLM for FakeLLM {
+    fn name(&self) -> &'static str {
+        "Fake"
+    }
+
+    async fn generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        let call = FakeCall {
+            messages: input,
+            stop,
+            options,
+        };
+        let prompt = call.prompt();
+        let timeout = call.options.timeout;
+        self.calls.lock().unwrap().push(call);
+        match (self.delay, timeout) {
+            (Some(delay), Some(timeout)) if timeout < delay => {
+                tokio::time::sleep(timeout).await;
+                return Err(LlmError::Timeout(timeout));
+            }
+            (Some(delay), _) => tokio::time::sleep(delay).await,
+            _ => {}
+        }
+
+        let response = match self.rules.iter().find(|(re, _)| re.is_match(&prompt)) {
+            Some((_, response)) => response,
+            None => self
+                .responses
+                .get(self.next.fetch_add(1, Ordering::SeqCst))
+                .ok_or_else(|| {
+                    LlmError::InvalidRequest(format!(
+                        "FakeLLM: no scripted response for prompt: {}",
+                        prompt
+                    ))
+                })?,
+        };
+        let mut generation = Result::<Generation, LlmError>::from(response.clone())?;
+        generation.usage = generation.usage.or(self.usage);
+        Ok(generation)
+    }
+}
+
+#[tokio::test]
+async fn test_fake_llm() {
+    let llm = FakeLLM::new(["first", "second"]).on(r"(?i)weather", "sunny");
+
+    let ask = |text: &str| {
+        llm.generate(
+            vec![Message {
+                role: Role::User,
+                content: text.into(),
+                ..Default::default()
+            }],
+            vec!["stop".to_string()],
+            Default::default(),
+        )
+    };
+    assert_eq!(ask("hi").await.unwrap().text[0].content, "first");
+    assert_eq!(ask("Weather today?").await.unwrap().text[0].content, "sunny");
+    assert_eq!(ask("hi").await.unwrap().text[0].content, "second");
+    assert!(ask("hi").await.is_err());
+
+    let calls = llm.calls();
+    assert_eq!(calls.len(), 4);
+    assert_eq!(calls[1].prompt(), "Weather today?");
+    assert_eq!(calls[1].stop, vec!["stop".to_string()]);
+}
@@ -0,0 +1,216 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::schema::{Generation, Message};
+
+use super::{ErrorClass, GenerateOptions, GenerationStream, LlmError, LLM};
+
+/// FallbackLLM sends a call to the primary llm and, when it fails with one of the
+/// `fallback_on` error classes or does not answer within `timeout`, to the fallback llm.
+/// nest it for longer lists, e.g. `FallbackLLM::new(openai, FallbackLLM::new(glm, local))`.
+/// `info.provider` is set to the label of the llm that answered, its name when it has no label
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FallbackLLM<L1: LLM, L2: LLM> {
+    pub primary: L1,
+    pub fallback: L2,
+    pub fallback_on: Vec<ErrorClass>,
+    /// time limit of the primary llm, for a stream the time until the first delta
+    pub timeout: Option<Duration>,
+    /// tells the two llms apart when they share a name, e.g. two OpenAIClients on different servers
+    #[serde(default)]
+    pub primary_label: Option<String>,
+    #[serde(default)]
+    pub fallback_label: Option<String>,
+}
+
+impl<L1: LLM, L2: LLM> FallbackLLM<L1, L2> {
+    /// new falls back on provider outages: transport, timeout, server, rate limit, quota and auth errors
+    pub fn new(primary: L1, fallback: L2) -> Self {
+        Self {
+            primary,
+            fallback,
+            fallback_on: vec![
+                ErrorClass::Transport,
+                ErrorClass::Timeout,
+                ErrorClass::Provider,
+                ErrorClass::RateLimited,
+                ErrorClass::QuotaExceeded,
+                ErrorClass::Auth,
+            ],
+            timeout: None,
+            primary_label: None,
+            fallback_label: None,
+        }
+    }
+
+    /// with_labels names the two llms in `info.provider` and in the warnings
+    pub fn with_labels(self, primary: impl Into<String>, fallback: impl Into<String>) -> Self {
+        Self {
+            primary_label: Some(primary.into()),
+            fallback_label: Some(fallback.into()),
+            ..self
+        }
+    }
+
+    fn primary_label(&self) -> String {
+        self.primary_label.clone().unwrap_or_else(|| self.primary.name().to_string())
+    }
+
+    fn fallback_label(&self) -> String {
+        self.fallback_label.clone().unwrap_or_else(|| self.fallback.name().to_string())
+    }
+
+    async fn generate_primary(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.primary.generate(input, stop, options))
+                .await
+                .unwrap_or(Err(LlmError::Timeout(timeout))),
+            None => self.primary.generate(input, stop, options).await,
+        }
+    }
+}
+
+/// set_provider records which llm answered, unless a nested combinator already did
+fn set_provider(generation: &mut Generation, provider: &str) {
+    let answered = generation
+        .info
+        .as_ref()
+        .is_some_and(|info| info.get("provider").is_some());
+    if !answered {
+        generation.set_info("provider", provider);
+    }
+}
+
+#[async_trait::async_trait]
+impl<L1: LLM, L2: LLM> LLM for FallbackLLM<L1, L2> {
+    fn name(&self) -> &'static str {
+        self.primary.name()
+    }
+
+    async fn generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        let (mut generation, provider) = match self.generate_primary(input.clone(), stop.clone(), options.clone()).await {
+            Ok(generation) => (generation, self.primary_label()),
+            Err(e) if self.fallback_on.contains(&e.class()) => {
+                println!(
+                    "Warning: FallbackLLM: {} failed: {}, falling back to {}",
+                    self.primary_label(),
+                    e,
+                    self.fallback_label()
+                );
+                (self.fallback.generate(input, stop, options).await?, self.fallback_label())
+            }
+            Err(e) => return Err(e),
+        };
+        set_provider(&mut generation, &provider);
+        Ok(generation)
+    }
+
+    /// only a failure before the first delta falls back, a stream broken in the middle is passed through
+    async fn generate_stream(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> GenerationStream {
+        let mut stream = self.primary.generate_stream(input.clone(), stop.clone(), options.clone()).await;
+        let first = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, stream.next())
+                .await
+                .unwrap_or(Some(Err(LlmError::Timeout(timeout)))),
+            None => stream.next().await,
+        };
+        let (stream, provider) = match first {
+            Some(Err(e)) if self.fallback_on.contains(&e.class()) => {
+                println!(
+                    "Warning: FallbackLLM: {} failed: {}, falling back to {}",
+                    self.primary_label(),
+                    e,
+                    self.fallback_label()
+                );
+                (self.fallback.generate_stream(input, stop, options).await, self.fallback_label())
+            }
+            first => (
+                Box::pin(futures::stream::iter(first).chain(stream)) as GenerationStream,
+                self.primary_label(),
+            ),
+        };
+        Box::pin(stream.map(move |delta| {
+            delta.map(|mut delta| {
+                set_provider(&mut delta, &provider);
+                delta
+            })
+        }))
+    }
+}
+
+#[tokio::test]
+async fn test_fallback_llm() {
+    use super::fake::FakeLLM;
+
+    let overloaded = || LlmError::Provider {
+        status: Some(503),
+        message: "overloaded".to_string(),
+    };
+    let llm = FallbackLLM::new(FakeLLM::new([overloaded()]), FakeLLM::new(["from fallback"]))
+        .with_labels("primary", "fallback");
+    let res = llm.generate(vec![], vec![], Default::default()).await.unwrap();
+    assert_eq!(res.text[0].content, "from fallback");
+    assert_eq!(res.info.unwrap()["provider"], "fallback");
+    assert_eq!(llm.fallback.calls().len(), 1);
+
+    // without labels the name of the llm is recorded
+    let llm = FallbackLLM::new(FakeLLM::new(["from primary"]), FakeLLM::new(["from fallback"]));
+    let res = llm.generate(vec![], vec![], Default::default()).await.unwrap();
+    assert_eq!(res.text[0].content, "from primary");
+    assert_eq!(res.info.unwrap()["provider"], "Fake");
+
+    // a stream failing before its first delta falls back
+    let llm = FallbackLLM::new(FakeLLM::new([overloaded()]), FakeLLM::new(["streamed"]))
+        .with_labels("primary", "fallback");
+    let deltas = llm
+        .generate_stream(vec![], vec![], Default::default())
+        .await
+        .collect::<Vec<_>>()
+        .await;
+    assert!(!deltas.is_empty());
+    let answer = deltas
+        .into_iter()
+        .map(|delta| delta.unwrap())
+        .inspect(|delta| assert_eq!(delta.info.as_ref().unwrap()["provider"], "fallback"))
+        .filter_map(|delta| delta.text.first().map(|m| m.content.to_string()))
+        .collect::<String>();
+    assert_eq!(answer, "streamed");
+    assert_eq!(llm.fallback.calls().len(), 1);
+
+    // a bad request would fail on the fallback as well
+    let llm = FallbackLLM::new(
+        FakeLLM::new([LlmError::InvalidRequest("bad".to_string())]),
+        FakeLLM::new(["from fallback"]),
+    );
+    assert!(matches!(
+        llm.generate(vec![], vec![], Default::default()).await,
+        Err(LlmError::InvalidRequest(_))
+    ));
+    assert!(llm.fallback.calls().is_empty());
+
+    let mut slow = FakeLLM::new(["too late"]);
+    slow.delay = Some(Duration::from_secs(10));
+    let llm = FallbackLLM {
+        timeout: Some(Duration::from_millis(10)),
+        ..FallbackLLM::new(slow, FakeLLM::new(["in time"]))
+    };
+    let res = llm.generate(vec![], vec![], Default::default()).await.unwrap();
+    assert_eq!(res.text[0].content, "in time");
+}
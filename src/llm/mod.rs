@@ -1,20 +1,86 @@
-use serde::Serialize;
+use std::pin::Pin;
+
+use futures::{Stream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
 
 use crate::schema::{Message, Generation};
 
+pub mod cache;
+pub mod cassette;
 pub mod client;
+pub mod dynamic;
+pub mod error;
+pub mod fake;
+pub mod fallback;
+pub mod options;
+pub mod pool;
+pub mod registry;
+pub mod retry;
+pub mod structured;
+
+pub use dynamic::DynLLM;
+pub use error::{ErrorClass, LlmError};
+pub use options::{GenerateOptions, ResponseFormat};
 
+/// GenerationStream yields incremental generations, every item carries the newly produced
+/// delta of each choice (at the choice index in `text`), concatenating them gives the full answer
+pub type GenerationStream = Pin<Box<dyn Stream<Item = Result<Generation, LlmError>> + Send>>;
 
 #[async_trait::async_trait]
 pub trait LLM: Serialize + Send + Sync {
     fn name(&self) -> &'static str;
-    async fn generate(&self, input: Vec<Message>, stop: Vec<String>) -> Generation;
-    // async fn generate_stream(&self, input: Vec<Message>, stop: Vec<String>) -> Pin<Box<dyn Stream<Item = Generation> + Send + Sync>>;
+    /// generate answers the input, the options that are set override the settings of the client
+    async fn generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError>;
+    /// generate_stream yields the answer piece by piece while the provider is producing it,
+    /// default implementation yields the whole generation as a single item,
+    /// a failed item ends the stream
+    async fn generate_stream(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> GenerationStream {
+        let generation = self.generate(input, stop, options).await;
+        Box::pin(futures::stream::once(async move { generation }))
+    }
+    /// generate_structured asks for json in `format` and deserializes it,
+    /// answers that do not parse are repaired, see `structured::generate_structured`
+    async fn generate_structured<T: DeserializeOwned>(
+        &self,
+        input: Vec<Message>,
+        format: &str,
+        options: GenerateOptions,
+    ) -> Result<(T, Generation), LlmError>
+    where
+        Self: Sized,
+    {
+        structured::generate_structured(self, input, format, options, structured::MAX_REPAIRS).await
+    }
 }
 
 #[async_trait::async_trait]
 pub trait Embedding: Serialize + Send + Sync {
     fn name(&self) -> &'static str;
+    /// dimension is the length of every vector produced, vector stores check their collections against it
+    fn dimension(&self) -> usize;
     async fn encode(&self, input: String) -> Vec<f32>;
+    /// encode_batch returns the vectors of all inputs in input order,
+    /// default implementation runs at most `batch_concurrency` encode calls at the same time,
+    /// providers with a native batch api should override it
+    async fn encode_batch(&self, inputs: Vec<String>) -> Vec<Vec<f32>> {
+        futures::stream::iter(inputs)
+            .map(|input| self.encode(input))
+            .buffered(self.batch_concurrency().max(1))
+            .collect()
+            .await
+    }
+    /// batch_concurrency is how many encode calls the default encode_batch keeps in flight, 0 counts as 1
+    fn batch_concurrency(&self) -> usize {
+        8
+    }
 }
-
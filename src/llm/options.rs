@@ -0,0 +1,122 @@
+use std::{ops::RangeInclusive, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use super::LlmError;
+
+/// ResponseFormat is the shape the answer must take
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// plain text, the default of every server, so it is not sent at all
+    Text,
+    /// any json object, the prompt should still ask for json
+    JsonObject,
+    /// json following `schema`, only supported by some OpenAI models
+    JsonSchema {
+        name: String,
+        schema: serde_json::Value,
+    },
+}
+
+/// GenerateOptions are the sampling settings of a single call, every field that is set
+/// overrides the setting of the client, e.g. a chain step that has to run cold:
+/// `GenerateOptions { temperature: Some(0.0), ..Default::default() }`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenerateOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>, // min: 0, max: 2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>, // min: 0, max: 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u16>, // min: 1
+    /// how many candidate answers to return in `Generation.text`, providers without
+    /// multiple choices return one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u8>, // min: 1
+    /// best effort determinism, same seed and options should give the same answer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>, // min: -2, max: 2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>, // min: -2, max: 2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    /// return the logprob of every generated token in `Generation.logprobs`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    /// how many of the most likely alternatives to return with every token, implies `logprobs`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u8>, // min: 0, max: 20
+    /// time limit of the call, it fails with LlmError::Timeout when passed.
+    /// not part of the request, so cached and recorded answers ignore it
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
+}
+
+/// check_range fails with InvalidRequest when the option is set and out of range
+pub(crate) fn check_range(name: &str, value: Option<f32>, range: RangeInclusive<f32>) -> Result<(), LlmError> {
+    match value {
+        Some(value) if !range.contains(&value) => Err(LlmError::InvalidRequest(format!(
+            "{} must be between {} and {}, got {}",
+            name,
+            range.start(),
+            range.end(),
+            value
+        ))),
+        _ => Ok(()),
+    }
+}
+
+impl GenerateOptions {
+    /// validate checks the ranges every provider accepts, providers check their narrower ranges themselves
+    pub fn validate(&self) -> Result<(), LlmError> {
+        check_range("temperature", self.temperature, 0.0..=2.0)?;
+        check_range("top_p", self.top_p, 0.0..=1.0)?;
+        check_range("presence_penalty", self.presence_penalty, -2.0..=2.0)?;
+        check_range("frequency_penalty", self.frequency_penalty, -2.0..=2.0)?;
+        if self.max_tokens == Some(0) {
+            return Err(LlmError::InvalidRequest("max_tokens must be at least 1".to_string()));
+        }
+        if self.n == Some(0) {
+            return Err(LlmError::InvalidRequest("n must be at least 1".to_string()));
+        }
+        if self.top_logprobs.is_some_and(|top| top > 20) {
+            return Err(LlmError::InvalidRequest("top_logprobs must be at most 20".to_string()));
+        }
+        if self.logprobs == Some(false) && self.top_logprobs.is_some() {
+            return Err(LlmError::InvalidRequest("top_logprobs needs logprobs, got logprobs: false".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_generate_options() {
+    let options = GenerateOptions {
+        temperature: Some(0.0),
+        response_format: Some(ResponseFormat::JsonObject),
+        ..Default::default()
+    };
+    assert!(options.validate().is_ok());
+    assert_eq!(
+        serde_json::to_value(&options).unwrap(),
+        serde_json::json!({"temperature": 0.0, "response_format": {"type": "json_object"}})
+    );
+
+    let options = GenerateOptions {
+        top_p: Some(1.5),
+        ..Default::default()
+    };
+    assert!(matches!(options.validate(), Err(LlmError::InvalidRequest(_))));
+
+    // openai rejects top_logprobs with logprobs turned off
+    let options = GenerateOptions {
+        logprobs: Some(false),
+        top_logprobs: Some(3),
+        ..Default::default()
+    };
+    assert!(matches!(options.validate(), Err(LlmError::InvalidRequest(_))));
+}
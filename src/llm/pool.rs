@@ -0,0 +1,192 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::schema::{Generation, Message};
+
+use super::{ErrorClass, GenerateOptions, GenerationStream, LlmError, LLM};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolStrategy {
+    /// every client in turn
+    #[default]
+    RoundRobin,
+    /// the client that has been sent the fewest calls
+    LeastUsed,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    next: usize,
+    used: Vec<u64>,
+    cooling_until: Vec<Option<Instant>>,
+}
+
+/// PoolLLM spreads calls over clients of the same provider holding different api keys,
+/// e.g. OpenAIClients with different `config.api_key`. a client failing with one of the
+/// `cooldown_on` error classes is taken out of rotation for `cooldown` and the call
+/// is sent to the next client, the last client's failure is returned as it is
+#[derive(Debug, Serialize)]
+pub struct PoolLLM<L: LLM> {
+    pub clients: Vec<L>,
+    pub strategy: PoolStrategy,
+    pub cooldown: Duration,
+    pub cooldown_on: Vec<ErrorClass>,
+    #[serde(skip)]
+    state: Mutex<PoolState>,
+}
+
+impl<L: LLM> PoolLLM<L> {
+    /// new rotates round robin and cools a client down for a minute after auth or quota errors
+    pub fn new(clients: Vec<L>) -> Self {
+        Self {
+            clients,
+            strategy: PoolStrategy::RoundRobin,
+            cooldown: Duration::from_secs(60),
+            cooldown_on: vec![ErrorClass::Auth, ErrorClass::QuotaExceeded],
+            state: Default::default(),
+        }
+    }
+
+    /// select picks the next available client that was not tried yet for this call,
+    /// when no client is left the error tells how long to wait for one to cool down
+    fn select(&self, tried: &[usize]) -> Result<usize, LlmError> {
+        if self.clients.is_empty() {
+            return Err(LlmError::InvalidRequest("PoolLLM has no clients".to_string()));
+        }
+        let n = self.clients.len();
+        let mut state = self.state.lock().unwrap();
+        state.used.resize(n, 0);
+        state.cooling_until.resize(n, None);
+
+        let now = Instant::now();
+        let available = (0..n)
+            .filter(|i| !tried.contains(i))
+            .filter(|i| !matches!(state.cooling_until[*i], Some(until) if until > now));
+        let selected = match self.strategy {
+            PoolStrategy::RoundRobin => available.min_by_key(|i| (i + n - state.next) % n),
+            PoolStrategy::LeastUsed => available.min_by_key(|i| state.used[*i]),
+        };
+        match selected {
+            Some(i) => {
+                state.next = (i + 1) % n;
+                state.used[i] += 1;
+                Ok(i)
+            }
+            None => Err(LlmError::RateLimited {
+                message: format!("PoolLLM: none of the {} clients is available", n),
+                retry_after: state
+                    .cooling_until
+                    .iter()
+                    .flatten()
+                    .min()
+                    .map(|until| until.saturating_duration_since(now)),
+            }),
+        }
+    }
+
+    /// cool_down takes the client out of rotation if the error asks for it,
+    /// and reports whether the call should go to another client
+    fn cool_down(&self, i: usize, e: &LlmError) -> bool {
+        if !self.cooldown_on.contains(&e.class()) {
+            return false;
+        }
+        println!(
+            "Warning: PoolLLM: client {} failed: {}, cooling down for {:?}",
+            i, e, self.cooldown
+        );
+        self.state.lock().unwrap().cooling_until[i] = Some(Instant::now() + self.cooldown);
+        true
+    }
+}
+
+#[async_trait::async_trait]
+impl<L: LLM> LLM for PoolLLM<L> {
+    fn name(&self) -> &'static str {
+        self.clients.first().map_or("Pool", |client| client.name())
+    }
+
+    async fn generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        let mut tried = Vec::new();
+        loop {
+            let i = self.select(&tried)?;
+            match self.clients[i].generate(input.clone(), stop.clone(), options.clone()).await {
+                Err(e) if self.cool_down(i, &e) && tried.len() + 1 < self.clients.len() => tried.push(i),
+                res => return res,
+            }
+        }
+    }
+
+    /// only a failure before the first delta moves to another client
+    async fn generate_stream(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> GenerationStream {
+        let mut tried = Vec::new();
+        loop {
+            let i = match self.select(&tried) {
+                Ok(i) => i,
+                Err(e) => return Box::pin(futures::stream::once(async move { Err(e) })),
+            };
+            let mut stream = self.clients[i].generate_stream(input.clone(), stop.clone(), options.clone()).await;
+            match stream.next().await {
+                Some(Err(e)) if self.cool_down(i, &e) && tried.len() + 1 < self.clients.len() => {
+                    tried.push(i)
+                }
+                first => return Box::pin(futures::stream::iter(first).chain(stream)),
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_pool_llm() {
+    use super::fake::FakeLLM;
+
+    let pool = PoolLLM::new(vec![
+        FakeLLM::new(["a1", "a2"]),
+        FakeLLM::new(["b1", "b2"]),
+    ]);
+    let mut answers = Vec::new();
+    for _ in 0..4 {
+        let res = pool.generate(vec![], vec![], Default::default()).await.unwrap();
+        answers.push(res.text[0].content.to_string());
+    }
+    assert_eq!(answers, vec!["a1", "b1", "a2", "b2"]);
+
+    // the first key is out of quota, it is skipped until the cooldown is over
+    let pool = PoolLLM::new(vec![
+        FakeLLM::new([LlmError::QuotaExceeded("no balance".to_string())]),
+        FakeLLM::new(["b1", "b2"]),
+    ]);
+    assert_eq!(pool.generate(vec![], vec![], Default::default()).await.unwrap().text[0].content, "b1");
+    assert_eq!(pool.generate(vec![], vec![], Default::default()).await.unwrap().text[0].content, "b2");
+    assert_eq!(pool.clients[0].calls().len(), 1);
+
+    // every key failed, the caller is told when to try again
+    let pool = PoolLLM::new(vec![FakeLLM::new([LlmError::Auth("revoked".to_string())])]);
+    assert!(matches!(pool.generate(vec![], vec![], Default::default()).await, Err(LlmError::Auth(_))));
+    assert!(matches!(
+        pool.generate(vec![], vec![], Default::default()).await,
+        Err(LlmError::RateLimited { retry_after: Some(_), .. })
+    ));
+
+    // no client at all is a configuration error, waiting would not help
+    let pool = PoolLLM::<FakeLLM>::new(vec![]);
+    assert!(matches!(
+        pool.generate(vec![], vec![], Default::default()).await,
+        Err(LlmError::InvalidRequest(_))
+    ));
+}
@@ -0,0 +1,138 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    client::{
+        glm::GLMClient,
+        local::LocalClient,
+        openai::{OpenAIClient, OpenAIConfig},
+    },
+    DynLLM,
+};
+
+/// Secrets resolves api keys by name, from the secrets file first and the environment then
+#[derive(Debug, Clone, Default)]
+pub struct Secrets {
+    pub values: BTreeMap<String, String>,
+}
+
+impl Secrets {
+    /// from_file reads a secrets file in `.env` format, `NAME=value` per line
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(Self {
+            values: dotenvy::from_path_iter(path)?.collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.values
+            .get(name)
+            .cloned()
+            .or_else(|| std::env::var(name).ok())
+    }
+}
+
+/// LLMConfig is an llm client described in config, the `provider` field picks the client
+/// and the other fields are the client's own, e.g.
+/// `{"provider": "openai", "model": "gpt-4o", "temperature": 0.2}`.
+/// keys never live in the config, they are resolved from Secrets by `build`:
+/// `OPENAI_API_KEY` (and `OPENAI_API_BASE`, `OPENAI_ORG_ID`) for openai,
+/// `ZHIPUAI_API_KEY` for glm and `LOCAL_LLM_API_KEY` (sent as bearer token) for local
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "provider")]
+pub enum LLMConfig {
+    #[serde(rename = "openai")]
+    OpenAI(OpenAIClient),
+    #[serde(rename = "glm")]
+    GLM(GLMClient),
+    #[serde(rename = "local")]
+    Local(LocalClient),
+}
+
+impl LLMConfig {
+    /// with_secrets fills in the keys of the client
+    pub fn with_secrets(self, secrets: &Secrets) -> Self {
+        match self {
+            Self::OpenAI(mut client) => {
+                if let Some(api_key) = secrets.get("OPENAI_API_KEY") {
+                    client.config.api_key = api_key;
+                }
+                // an api base set in the config wins over the secret
+                if client.config.api_base == OpenAIConfig::default().api_base {
+                    if let Some(api_base) = secrets.get("OPENAI_API_BASE") {
+                        client.config.api_base = api_base;
+                    }
+                }
+                if let Some(org_id) = secrets.get("OPENAI_ORG_ID") {
+                    client.config.org_id = org_id;
+                }
+                Self::OpenAI(client)
+            }
+            Self::GLM(mut client) => {
+                client.api_key = secrets.get("ZHIPUAI_API_KEY").or(client.api_key);
+                Self::GLM(client)
+            }
+            Self::Local(mut client) => {
+                if let Some(api_key) = secrets.get("LOCAL_LLM_API_KEY") {
+                    client
+                        .headers
+                        .insert("Authorization".to_string(), format!("Bearer {}", api_key));
+                }
+                Self::Local(client)
+            }
+        }
+    }
+
+    /// build resolves the keys and returns the ready client
+    pub fn build(self, secrets: &Secrets) -> Box<dyn DynLLM> {
+        match self.with_secrets(secrets) {
+            Self::OpenAI(client) => Box::new(client),
+            Self::GLM(client) => Box::new(client),
+            Self::Local(client) => Box::new(client),
+        }
+    }
+}
+
+#[test]
+fn test_llm_config() {
+    use crate::btreemap;
+
+    let secrets = Secrets {
+        values: btreemap! {
+            "OPENAI_API_KEY".to_string() => "sk-test".to_string(),
+        },
+    };
+    let config: LLMConfig =
+        serde_json::from_str(r#"{"provider": "openai", "model": "gpt-4o", "temperature": 0.2}"#).unwrap();
+    let LLMConfig::OpenAI(client) = config.clone().with_secrets(&secrets) else {
+        panic!("not an openai config");
+    };
+    assert_eq!(client.model, "gpt-4o");
+    assert_eq!(client.temperature, Some(0.2));
+    assert_eq!(client.config.api_key, "sk-test");
+
+    let llm = config.build(&secrets);
+    assert_eq!(llm.dyn_name(), "OpenAI");
+    assert_eq!(llm.dyn_params()["model"], "gpt-4o");
+
+    let secrets = Secrets {
+        values: btreemap! {
+            "OPENAI_API_BASE".to_string() => "https://proxy.example.com/v1".to_string(),
+        },
+    };
+    let config: LLMConfig = serde_json::from_str(
+        r#"{"provider": "openai", "model": "llama3", "api_base": "http://localhost:8080/v1"}"#,
+    )
+    .unwrap();
+    assert_eq!(config.build(&secrets).dyn_params()["api_base"], "http://localhost:8080/v1");
+    let config: LLMConfig = serde_json::from_str(r#"{"provider": "openai"}"#).unwrap();
+    assert_eq!(config.build(&secrets).dyn_params()["api_base"], "https://proxy.example.com/v1");
+
+    let config: LLMConfig = serde_json::from_str(
+        r#"{"provider": "local", "api_base": "http://localhost:11434/v1", "model": "llama3"}"#,
+    )
+    .unwrap();
+    assert_eq!(config.build(&secrets).dyn_name(), "Local");
+    assert!(serde_json::from_str::<LLMConfig>(r#"{"provider": "nope"}"#).is_err());
+}
@@ -0,0 +1,200 @@
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::schema::{Generation, Message};
+
+use super::{GenerateOptions, GenerationStream, LlmError, LLM};
+
+/// RetryLLM retries transient failures (rate limits, transport and server errors) of the wrapped llm
+/// with exponential backoff and jitter, a wait hint from the provider is used instead when given.
+/// a hint longer than `max_backoff` is not waited for, the error is returned
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryLLM<L: LLM> {
+    pub llm: L,
+    /// how many times a request is sent again after the first failure
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    /// total time budget for all attempts, no retry is started once it would be exceeded
+    pub max_elapsed: Option<Duration>,
+}
+
+impl<L: LLM> RetryLLM<L> {
+    pub fn new(llm: L) -> Self {
+        Self {
+            llm,
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+            multiplier: 2.0,
+            max_elapsed: None,
+        }
+    }
+
+    /// backoff returns how long to wait before the given retry (starting at 0),
+    /// or None if the error should not be retried or the budget is spent
+    fn backoff(&self, retry: u32, started: Instant, e: &LlmError) -> Option<Duration> {
+        if retry >= self.max_retries || !e.is_transient() {
+            return None;
+        }
+        let delay = match e {
+            LlmError::RateLimited {
+                retry_after: Some(retry_after),
+                ..
+            } if *retry_after > self.max_backoff => return None,
+            LlmError::RateLimited {
+                retry_after: Some(retry_after),
+                ..
+            } => *retry_after,
+            _ => {
+                let exp = self.initial_backoff.as_secs_f64() * self.multiplier.powi(retry as i32);
+                let capped = exp.min(self.max_backoff.as_secs_f64());
+                // equal jitter: half fixed, half random, so fan-out calls do not retry in lockstep
+                Duration::from_secs_f64(capped / 2.0 + rand::thread_rng().gen_range(0.0..=capped / 2.0))
+            }
+        };
+        match self.max_elapsed {
+            Some(max_elapsed) if started.elapsed() + delay > max_elapsed => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<L: LLM> LLM for RetryLLM<L> {
+    fn name(&self) -> &'static str {
+        self.llm.name()
+    }
+
+    async fn generate(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        let started = Instant::now();
+        let mut retry = 0;
+        loop {
+            match self.llm.generate(input.clone(), stop.clone(), options.clone()).await {
+                Ok(generation) => return Ok(generation),
+                Err(e) => {
+                    let Some(delay) = self.backoff(retry, started, &e) else {
+                        return Err(e);
+                    };
+                    println!("Warning: RetryLLM: {}, retrying in {:?}", e, delay);
+                    tokio::time::sleep(delay).await;
+                    retry += 1;
+                }
+            }
+        }
+    }
+
+    /// only a failure before the first delta is retried, a stream broken in the middle is passed through
+    async fn generate_stream(
+        &self,
+        input: Vec<Message>,
+        stop: Vec<String>,
+        options: GenerateOptions,
+    ) -> GenerationStream {
+        let started = Instant::now();
+        let mut retry = 0;
+        loop {
+            let mut stream = self.llm.generate_stream(input.clone(), stop.clone(), options.clone()).await;
+            match stream.next().await {
+                Some(Err(e)) => {
+                    let Some(delay) = self.backoff(retry, started, &e) else {
+                        return Box::pin(futures::stream::once(async move { Err(e) }));
+                    };
+                    println!("Warning: RetryLLM: {}, retrying in {:?}", e, delay);
+                    tokio::time::sleep(delay).await;
+                    retry += 1;
+                }
+                first => return Box::pin(futures::stream::iter(first).chain(stream)),
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_retry_llm() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use crate::schema::Role;
+
+    #[derive(Serialize)]
+    struct FlakyLLM {
+        failures: u32,
+        retry_after: Duration,
+        #[serde(skip)]
+        calls: AtomicU32,
+    }
+
+    #[async_trait::async_trait]
+    impl LLM for FlakyLLM {
+        fn name(&self) -> &'static str {
+            "Flaky"
+        }
+        async fn generate(
+            &self,
+            _input: Vec<Message>,
+            _stop: Vec<String>,
+            _options: GenerateOptions,
+        ) -> Result<Generation, LlmError> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(LlmError::RateLimited {
+                    message: "slow down".to_string(),
+                    retry_after: Some(self.retry_after),
+                });
+            }
+            Ok(Generation {
+                text: vec![Message {
+                    role: Role::Assistant,
+                    content: "ok".into(),
+                    ..Default::default()
+                }],
+                usage: None,
+                info: None,
+                logprobs: vec![],
+            })
+        }
+    }
+
+    let llm = RetryLLM::new(FlakyLLM {
+        failures: 2,
+        retry_after: Duration::from_millis(1),
+        calls: AtomicU32::new(0),
+    });
+    let res = llm.generate(vec![], vec![], Default::default()).await.unwrap();
+    assert_eq!(res.text[0].content, "ok");
+    assert_eq!(llm.llm.calls.load(Ordering::SeqCst), 3);
+
+    let llm = RetryLLM {
+        max_retries: 1,
+        ..RetryLLM::new(FlakyLLM {
+            failures: 2,
+            retry_after: Duration::from_millis(1),
+            calls: AtomicU32::new(0),
+        })
+    };
+    assert!(matches!(
+        llm.generate(vec![], vec![], Default::default()).await,
+        Err(LlmError::RateLimited { .. })
+    ));
+    assert_eq!(llm.llm.calls.load(Ordering::SeqCst), 2);
+
+    // "try again in 1h" is given up on at once instead of sleeping for an hour
+    let llm = RetryLLM::new(FlakyLLM {
+        failures: 1,
+        retry_after: Duration::from_secs(3600),
+        calls: AtomicU32::new(0),
+    });
+    assert!(matches!(
+        llm.generate(vec![], vec![], Default::default()).await,
+        Err(LlmError::RateLimited { .. })
+    ));
+    assert_eq!(llm.llm.calls.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,175 @@
+use serde::de::DeserializeOwned;
+
+use crate::schema::{usage::Usage, Generation, Message, Role};
+
+use super::{GenerateOptions, LlmError, ResponseFormat, LLM};
+
+/// how many times generate_structured asks the model to fix an answer that does not parse
+pub const MAX_REPAIRS: usize = 2;
+
+/// format_instructions tells the model to answer with json only, `format` is a json schema,
+/// an example or a plain description of the expected object
+pub fn format_instructions(format: &str) -> String {
+    format!(
+        "answer with a single json value and nothing else, no explanation and no markdown, in this format:\n{}",
+        format
+    )
+}
+
+/// extract_json finds the json in an answer, the content of a ```json fence if there is one,
+/// otherwise the first balanced object or array that parses
+pub fn extract_json(text: &str) -> Option<&str> {
+    let fenced = text.split("```").skip(1).step_by(2).map(|block| {
+        block
+            .strip_prefix("json")
+            .or_else(|| block.strip_prefix("JSON"))
+            .unwrap_or(block)
+            .trim()
+    });
+    let balanced = text
+        .char_indices()
+        .filter(|(_, c)| *c == '{' || *c == '[')
+        .filter_map(|(start, _)| balanced_end(&text[start..]).map(|end| &text[start..start + end]));
+    fenced
+        .chain(balanced)
+        .find(|candidate| serde_json::from_str::<serde::de::IgnoredAny>(candidate).is_ok())
+}
+
+/// balanced_end is the length of the object or array at the start of `text`,
+/// brackets inside strings are skipped
+fn balanced_end(text: &str) -> Option<usize> {
+    let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' | '[' => depth += 1,
+            '}' | ']' => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// parse_structured deserializes the json found in an answer
+pub fn parse_structured<T: DeserializeOwned>(text: &str) -> Result<T, String> {
+    let json = extract_json(text).ok_or_else(|| "no json found in the answer".to_string())?;
+    serde_json::from_str(json).map_err(|e| e.to_string())
+}
+
+/// generate_structured asks the llm for json in `format`, in json mode unless `options` ask for
+/// another response format, and deserializes the answer. an answer that does not parse is sent back with the error
+/// for at most `max_repairs` times. the returned generation is the last answer,
+/// its usage covers all attempts.
+/// json mode only allows an object at the top level, so `T` must be a struct or a map by default,
+/// pass `ResponseFormat::Text` in `options` for a list or a plain value, or for a local server
+/// that rejects `response_format`, the prompt alone then asks for json
+pub async fn generate_structured<T: DeserializeOwned>(
+    llm: &impl LLM,
+    mut input: Vec<Message>,
+    format: &str,
+    mut options: GenerateOptions,
+    max_repairs: usize,
+) -> Result<(T, Generation), LlmError> {
+    options.response_format = options.response_format.or(Some(ResponseFormat::JsonObject));
+    input.push(Message {
+        role: Role::User,
+        content: format_instructions(format).into(),
+        ..Default::default()
+    });
+    let mut usage: Vec<Option<Usage>> = Vec::new();
+    let mut repairs = 0;
+    loop {
+        let mut generation = llm.generate(input.clone(), vec![], options.clone()).await?;
+        usage.push(generation.usage);
+        let answer = generation
+            .text
+            .first()
+            .ok_or_else(|| LlmError::MalformedResponse("no answer".to_string()))?
+            .clone();
+        let e = match parse_structured(&answer.content.to_string()) {
+            Ok(value) => {
+                generation.usage = Usage::aggregate(usage);
+                return Ok((value, generation));
+            }
+            Err(e) if repairs >= max_repairs => {
+                return Err(LlmError::MalformedResponse(format!("{}: {}", e, answer.content)))
+            }
+            Err(e) => e,
+        };
+        println!("Warning: generate_structured: {}, asking for a repair", e);
+        input.push(answer);
+        input.push(Message {
+            role: Role::User,
+            content: format!("your answer could not be parsed: {}\n{}", e, format_instructions(format)).into(),
+            ..Default::default()
+        });
+        repairs += 1;
+    }
+}
+
+#[test]
+fn test_extract_json() {
+    let text = "Sure! here it is:\n```json\n{\"score\": 0.5}\n```\nhope it helps";
+    assert_eq!(extract_json(text), Some("{\"score\": 0.5}"));
+
+    let text = r#"the score is {"score": 0.9, "doc": "a {weird} doc]"} as requested"#;
+    assert_eq!(extract_json(text), Some(r#"{"score": 0.9, "doc": "a {weird} doc]"}"#));
+
+    // a bracket in the chatter before the json is skipped
+    assert_eq!(extract_json("[note] {\"a\": [1, 2]}"), Some("{\"a\": [1, 2]}"));
+    assert_eq!(extract_json("no json {here"), None);
+}
+
+#[tokio::test]
+async fn test_generate_structured() {
+    use super::fake::FakeLLM;
+
+    #[derive(serde::Deserialize)]
+    struct Score {
+        score: f64,
+    }
+
+    let mut llm = FakeLLM::new(["the score is 0.7", "oops, here: {\"score\": 0.7}"]);
+    llm.usage = Some(Usage {
+        prompt_tokens: 10,
+        completion_tokens: 5,
+        cost: None,
+    });
+    let (value, generation) = llm
+        .generate_structured::<Score>(vec![], r#"{"score": number}"#, Default::default())
+        .await
+        .unwrap();
+    assert_eq!(value.score, 0.7);
+    assert_eq!(generation.usage.unwrap().prompt_tokens, 20);
+    // the repair prompt carries the failed answer and the parse error
+    assert!(llm.calls()[1].prompt().contains("the score is 0.7\nyour answer could not be parsed"));
+    assert_eq!(llm.calls()[0].options.response_format, Some(ResponseFormat::JsonObject));
+
+    let llm = FakeLLM::new(["no", "still no"]);
+    let res = generate_structured::<Score>(&llm, vec![], "{}", Default::default(), 1).await;
+    assert!(matches!(res, Err(LlmError::MalformedResponse(_))));
+
+    // a list cannot be asked for in json mode, the caller turns it off
+    let llm = FakeLLM::new(["[1, 2]"]);
+    let options = GenerateOptions {
+        response_format: Some(ResponseFormat::Text),
+        ..Default::default()
+    };
+    let (value, _) = llm.generate_structured::<Vec<u8>>(vec![], "[number]", options).await.unwrap();
+    assert_eq!(value, vec![1, 2]);
+    assert_eq!(llm.calls()[0].options.response_format, Some(ResponseFormat::Text));
+}
@@ -18,17 +18,16 @@ pub struct PromptTemplate {
 
 impl PromptTemplate {
     /// Format the prompt template with the given values.
+    /// None if a variable without default value is missing.
     pub fn format(&self, values: &BTreeMap<String, String>) -> Option<String> {
-        // first asserting that all variables in the values
-        self.variables.iter().for_each(|(key, value)| {
-            if value.is_empty() {
-                assert!(
-                    values.contains_key(key),
-                    "missing variable {} in values",
-                    key
-                );
-            }
-        });
+        // first checking that all variables are in the values
+        if self
+            .variables
+            .iter()
+            .any(|(key, value)| value.is_empty() && !values.contains_key(key))
+        {
+            return None;
+        }
         let mut values = Cow::Borrowed(values);
         // then setting the default values
         self.variables.iter().for_each(|(key, value)| {
@@ -228,6 +227,8 @@ fn test_prompt_format() {
         println!("formatted: {:?}", formatted);
         assert_eq!(formatted, Some(result[i].to_string()));
     }
+    // a missing variable without default value cannot be formatted
+    assert_eq!(PromptTemplate::from(templates[1].to_string()).format(&vars[0]), None);
 }
 
 #[test]
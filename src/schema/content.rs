@@ -0,0 +1,120 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+/// Content of a message, either plain text or a list of parts mixing text and images.
+/// plain text serializes as a string, so messages without images keep their old format
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { url: String },
+    /// inline image, `data` is base64 encoded, `media_type` e.g. `image/png`
+    ImageBase64 { media_type: String, data: String },
+}
+
+impl Content {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Text(text) => text.is_empty(),
+            Self::Parts(parts) => parts.is_empty(),
+        }
+    }
+
+    pub fn has_images(&self) -> bool {
+        match self {
+            Self::Text(_) => false,
+            Self::Parts(parts) => parts
+                .iter()
+                .any(|part| !matches!(part, ContentPart::Text { .. })),
+        }
+    }
+
+    /// into_parts returns the content as a list of parts
+    pub fn into_parts(self) -> Vec<ContentPart> {
+        match self {
+            Self::Text(text) => vec![ContentPart::Text { text }],
+            Self::Parts(parts) => parts,
+        }
+    }
+}
+
+impl Default for Content {
+    fn default() -> Self {
+        Self::Text(String::new())
+    }
+}
+
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<&str> for Content {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+impl From<Vec<ContentPart>> for Content {
+    fn from(parts: Vec<ContentPart>) -> Self {
+        Self::Parts(parts)
+    }
+}
+
+/// only the text is displayed, text parts are joined with new lines and images are skipped
+impl Display for Content {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Text(text) => write!(f, "{}", text),
+            Self::Parts(parts) => {
+                let mut first = true;
+                for part in parts {
+                    if let ContentPart::Text { text } = part {
+                        if !first {
+                            writeln!(f)?;
+                        }
+                        write!(f, "{}", text)?;
+                        first = false;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl PartialEq<&str> for Content {
+    fn eq(&self, other: &&str) -> bool {
+        matches!(self, Self::Text(text) if text == other)
+    }
+}
+
+#[test]
+fn test_content_serde() {
+    let content: Content = serde_json::from_str(r#""hello""#).unwrap();
+    assert_eq!(content, "hello");
+
+    let content: Content = serde_json::from_str(
+        r#"[
+            {"type": "text", "text": "what is in the picture?"},
+            {"type": "image_url", "url": "https://example.com/cat.png"},
+            {"type": "image_base64", "media_type": "image/png", "data": "iVBORw0KGgo="}
+        ]"#,
+    )
+    .unwrap();
+    assert!(content.has_images());
+    assert_eq!(content.to_string(), "what is in the picture?");
+    assert_eq!(
+        serde_json::to_value(&content).unwrap()[1],
+        serde_json::json!({"type": "image_url", "url": "https://example.com/cat.png"})
+    );
+}
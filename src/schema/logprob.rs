@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+
+/// TokenLogprob is a generated token with its natural log probability
+/// and the most likely tokens the model could have chosen instead
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f64,
+}
+
+impl TokenLogprob {
+    /// probability_of is the probability that the model answered `word` at this position,
+    /// summed over the alternatives that only differ in case and surrounding whitespace,
+    /// e.g. "Yes", " yes" and "YES"
+    pub fn probability_of(&self, word: &str) -> f64 {
+        let same = |token: &str| token.trim().eq_ignore_ascii_case(word.trim());
+        if self.top_logprobs.is_empty() {
+            return if same(&self.token) { self.logprob.exp() } else { 0.0 };
+        }
+        self.top_logprobs
+            .iter()
+            .filter(|top| same(&top.token))
+            .map(|top| top.logprob.exp())
+            .sum()
+    }
+}
+
+/// sequence_logprob is the log probability of the whole sequence
+pub fn sequence_logprob(tokens: &[TokenLogprob]) -> f64 {
+    tokens.iter().map(|t| t.logprob).sum()
+}
+
+/// sequence_confidence is the geometric mean of the token probabilities, between 0 and 1,
+/// so long and short answers compare fairly. None for an empty sequence
+pub fn sequence_confidence(tokens: &[TokenLogprob]) -> Option<f64> {
+    if tokens.is_empty() {
+        return None;
+    }
+    Some((sequence_logprob(tokens) / tokens.len() as f64).exp())
+}
+
+#[test]
+fn test_sequence_confidence() {
+    let token = |token: &str, p: f64| TokenLogprob {
+        token: token.to_string(),
+        logprob: p.ln(),
+        top_logprobs: vec![],
+    };
+    let tokens = vec![token("Paris", 0.9), token(".", 0.4)];
+    assert!((sequence_confidence(&tokens).unwrap() - 0.6).abs() < 1e-9);
+    assert!((sequence_logprob(&tokens) - 0.36f64.ln()).abs() < 1e-9);
+    assert_eq!(sequence_confidence(&[]), None);
+
+    let answer = TokenLogprob {
+        top_logprobs: vec![
+            TopLogprob { token: "Yes".to_string(), logprob: 0.6f64.ln() },
+            TopLogprob { token: " yes".to_string(), logprob: 0.1f64.ln() },
+            TopLogprob { token: "No".to_string(), logprob: 0.25f64.ln() },
+        ],
+        ..token("Yes", 0.6)
+    };
+    assert!((answer.probability_of("yes") - 0.7).abs() < 1e-9);
+    assert!((answer.probability_of("no") - 0.25).abs() < 1e-9);
+}
@@ -3,16 +3,77 @@
 // pub mod examples;
 // pub mod documents;
 
+pub mod content;
+pub mod logprob;
 pub mod memory;
+pub mod tool;
+pub mod usage;
 
-use std::{collections::BTreeMap, str::FromStr};
+use std::{collections::BTreeMap, fmt::Display, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use self::{content::Content, logprob::{sequence_confidence, TokenLogprob}, tool::ToolCall, usage::Usage};
+
+/// Role of the message author, providers map it to their own role names.
+/// custom roles name the speakers of a character chat, they speak on the user side
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Role {
+    System,
+    #[default]
+    User,
+    Assistant,
+    Tool,
+    Custom(String),
+}
+
+impl From<&str> for Role {
+    fn from(role: &str) -> Self {
+        match role.to_lowercase().as_str() {
+            "system" => Self::System,
+            "user" | "human" => Self::User,
+            "assistant" | "bot" | "ai" => Self::Assistant,
+            "tool" | "function" => Self::Tool,
+            _ => Self::Custom(role.to_string()),
+        }
+    }
+}
+
+impl From<String> for Role {
+    fn from(role: String) -> Self {
+        role.as_str().into()
+    }
+}
+
+impl From<Role> for String {
+    fn from(role: Role) -> Self {
+        role.to_string()
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::System => write!(f, "system"),
+            Self::User => write!(f, "user"),
+            Self::Assistant => write!(f, "assistant"),
+            Self::Tool => write!(f, "tool"),
+            Self::Custom(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Message {
-    pub role: String,
-    pub content: String,
+    pub role: Role,
+    pub content: Content,
+    /// tool calls requested by the assistant
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// set on `tool` messages, the id of the tool call this message answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 
 impl FromStr for Message {
@@ -24,8 +85,9 @@ impl FromStr for Message {
         if let Some(role) = split.next() {
             if let Some(content) = split.next() {
                 return Ok(Message {
-                    role: role.to_string(),
-                    content: content.to_string(),
+                    role: role.into(),
+                    content: content.into(),
+                    ..Default::default()
                 });
             } else {
                 return Err("Invalid message".to_string());
@@ -36,8 +98,48 @@ impl FromStr for Message {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Generation {
     pub text: Vec<Message>,
+    /// tokens (and cost) spent to produce the generation, for a chain all llm calls it made
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// anything else the provider reported
     pub info: Option<serde_json::Value>,
+    /// token logprobs of every choice at its index in `text`,
+    /// empty unless asked for with `GenerateOptions.logprobs`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub logprobs: Vec<Vec<TokenLogprob>>,
+}
+
+impl Generation {
+    /// set_info sets a key of the info object, creating the object if there is no info yet,
+    /// info that is not an object is left untouched
+    pub fn set_info(&mut self, key: &str, value: impl Into<serde_json::Value>) {
+        match &mut self.info {
+            Some(serde_json::Value::Object(info)) => {
+                info.insert(key.to_string(), value.into());
+            }
+            None => self.info = Some(serde_json::json!({ key: value.into() })),
+            Some(_) => {}
+        }
+    }
+
+    /// confidence is the sequence confidence of the first choice, None without logprobs
+    pub fn confidence(&self) -> Option<f64> {
+        sequence_confidence(self.logprobs.first()?)
+    }
+}
+
+#[test]
+fn test_role_serde() {
+    let message = Message::from_str("SYSTEM: be nice").unwrap();
+    assert_eq!(message.role, Role::System);
+
+    let json = serde_json::to_string(&message).unwrap();
+    assert_eq!(json, r#"{"role":"system","content":"be nice"}"#);
+
+    let message: Message = serde_json::from_str(r#"{"role":"电棍","content":"hi"}"#).unwrap();
+    assert_eq!(message.role, Role::Custom("电棍".to_string()));
+    assert_eq!(serde_json::to_value(&message).unwrap()["role"], "电棍");
 }
@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+/// Tool describes a function the model may call, `parameters` is a JSON schema of its arguments
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+/// ToolChoice controls whether and which tool the model has to call
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolChoice {
+    None,
+    Auto,
+    Required,
+    /// force the model to call the tool with this name
+    Tool(String),
+}
+
+/// ToolCall is a call requested by the model, `arguments` is the JSON encoded argument object.
+/// the answer goes back to the model as a `tool` message carrying the same `id` in `tool_call_id`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+}
+
+impl ToolCall {
+    /// parse_arguments deserializes the arguments into the expected type
+    pub fn parse_arguments<T: serde::de::DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_str(&self.arguments)
+    }
+}
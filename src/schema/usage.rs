@@ -0,0 +1,146 @@
+use std::{collections::BTreeMap, ops::Add};
+
+use serde::{Deserialize, Serialize};
+
+/// Usage is what one or more llm calls consumed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    /// missing counts of partial usage reports are read as 0
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    /// in USD, None when any call could not be priced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    /// free is the usage of an answer served from a cache or a cassette, nothing was spent on it
+    pub fn free() -> Self {
+        Self {
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            cost: Some(0.0),
+        }
+    }
+
+    /// aggregate adds up the usage of many calls, None if no call reported usage.
+    /// cached answers add nothing
+    pub fn aggregate(usages: impl IntoIterator<Item = Option<Usage>>) -> Option<Usage> {
+        usages.into_iter().flatten().reduce(Add::add)
+    }
+
+    /// priced sets the cost of the call from the price table, if the model is listed
+    pub fn priced(self, prices: &PriceTable, model: &str) -> Self {
+        Self {
+            cost: prices.cost(model, &self).or(self.cost),
+            ..self
+        }
+    }
+}
+
+/// the cost of the sum is only known when every call was priced,
+/// a partial total would pass for the whole spend
+impl Add for Usage {
+    type Output = Usage;
+
+    fn add(self, other: Usage) -> Usage {
+        Usage {
+            prompt_tokens: self.prompt_tokens + other.prompt_tokens,
+            completion_tokens: self.completion_tokens + other.completion_tokens,
+            cost: self.cost.zip(other.cost).map(|(a, b)| a + b),
+        }
+    }
+}
+
+/// Price of a model in USD per million tokens
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// PriceTable maps model names to prices, a dated model like `gpt-4o-2024-08-06`
+/// is priced as the longest listed name it starts with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PriceTable {
+    pub prices: BTreeMap<String, Price>,
+}
+
+impl PriceTable {
+    pub fn get(&self, model: &str) -> Option<Price> {
+        self.prices
+            .iter()
+            .filter(|(name, _)| model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, price)| *price)
+    }
+
+    pub fn cost(&self, model: &str, usage: &Usage) -> Option<f64> {
+        let price = self.get(model)?;
+        Some(
+            (usage.prompt_tokens as f64 * price.prompt
+                + usage.completion_tokens as f64 * price.completion)
+                / 1_000_000.0,
+        )
+    }
+}
+
+/// openai list prices, update them or build your own table when they change
+impl Default for PriceTable {
+    fn default() -> Self {
+        let prices = [
+            ("gpt-4o", 2.5, 10.0),
+            ("gpt-4o-mini", 0.15, 0.6),
+            ("gpt-4-turbo", 10.0, 30.0),
+            ("gpt-4", 30.0, 60.0),
+            ("gpt-4-32k", 60.0, 120.0),
+            ("gpt-3.5-turbo", 0.5, 1.5),
+            ("text-embedding-3-small", 0.02, 0.0),
+            ("text-embedding-3-large", 0.13, 0.0),
+            ("text-embedding-ada-002", 0.1, 0.0),
+        ];
+        Self {
+            prices: prices
+                .into_iter()
+                .map(|(name, prompt, completion)| (name.to_string(), Price { prompt, completion }))
+                .collect(),
+        }
+    }
+}
+
+#[test]
+fn test_usage_cost() {
+    let prices = PriceTable::default();
+    let usage = Usage {
+        prompt_tokens: 1_000_000,
+        completion_tokens: 100_000,
+        cost: None,
+    };
+    assert_eq!(usage.priced(&prices, "gpt-4o-2024-08-06").cost, Some(3.5));
+    assert_eq!(usage.priced(&prices, "gpt-4o-mini").cost, Some(0.21));
+    assert_eq!(usage.priced(&prices, "llama3").cost, None);
+
+    let total = Usage::aggregate([
+        Some(usage.priced(&prices, "gpt-4o")),
+        None,
+        Some(usage.priced(&prices, "llama3")),
+    ])
+    .unwrap();
+    assert_eq!(total.prompt_tokens, 2_000_000);
+    assert_eq!(total.total_tokens(), 2_200_000);
+    assert_eq!(total.cost, None);
+    let total = Usage::aggregate([
+        Some(usage.priced(&prices, "gpt-4o")),
+        None,
+        Some(Usage::free()),
+    ])
+    .unwrap();
+    assert_eq!(total.cost, Some(3.5));
+    assert_eq!(Usage::aggregate([None, None]), None);
+}
@@ -0,0 +1,232 @@
+use std::{collections::HashMap, path::Path};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::schema::{
+    content::{Content, ContentPart},
+    Message, Role,
+};
+
+/// tokens every message costs on top of its role and content, openai chat format
+pub const TOKENS_PER_MESSAGE: usize = 3;
+/// tokens a message with a `name` costs on top of the name itself
+pub const TOKENS_PER_NAME: usize = 1;
+/// tokens priming the reply of the assistant, counted once per prompt
+pub const TOKENS_PER_REPLY: usize = 3;
+/// rough cost of an image, a low detail image in openai chat format
+pub const TOKENS_PER_IMAGE: usize = 85;
+
+/// pre-tokenization pattern of the cl100k_base vocabulary (gpt-3.5, gpt-4)
+pub const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+/// pre-tokenization pattern of the o200k_base vocabulary (gpt-4o)
+pub const O200K_PATTERN: &str = concat!(
+    r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+(?!\S)|\s+",
+);
+
+pub trait Tokenizer: Send + Sync {
+    fn encode(&self, text: &str) -> Vec<u32>;
+    fn decode(&self, tokens: &[u32]) -> String;
+    fn count_tokens(&self, text: &str) -> usize {
+        self.encode(text).len()
+    }
+    /// count_message_tokens counts a prompt the way chat models see it: every message costs
+    /// its role, name and content plus `TOKENS_PER_MESSAGE`, and the reply costs `TOKENS_PER_REPLY`
+    fn count_message_tokens(&self, messages: &[Message]) -> usize {
+        messages
+            .iter()
+            .map(|message| {
+                let role = match &message.role {
+                    Role::Custom(name) => {
+                        self.count_tokens("user") + self.count_tokens(name) + TOKENS_PER_NAME
+                    }
+                    role => self.count_tokens(&role.to_string()),
+                };
+                let content = match &message.content {
+                    Content::Text(text) => self.count_tokens(text),
+                    Content::Parts(parts) => parts
+                        .iter()
+                        .map(|part| match part {
+                            ContentPart::Text { text } => self.count_tokens(text),
+                            _ => TOKENS_PER_IMAGE,
+                        })
+                        .sum(),
+                };
+                let tool_calls = message
+                    .tool_calls
+                    .iter()
+                    .map(|call| self.count_tokens(&call.name) + self.count_tokens(&call.arguments))
+                    .sum::<usize>();
+                TOKENS_PER_MESSAGE + role + content + tool_calls
+            })
+            .sum::<usize>()
+            + TOKENS_PER_REPLY
+    }
+}
+
+/// BpeTokenizer is a byte pair encoding tokenizer for tiktoken style vocabularies
+pub struct BpeTokenizer {
+    encoder: HashMap<Vec<u8>, u32>,
+    decoder: HashMap<u32, Vec<u8>>,
+    pattern: fancy_regex::Regex,
+}
+
+impl BpeTokenizer {
+    /// new builds a tokenizer from the merge ranks and the pre-tokenization pattern,
+    /// every single byte has to be in the vocabulary so any text can be encoded
+    pub fn new(encoder: HashMap<Vec<u8>, u32>, pattern: &str) -> anyhow::Result<Self> {
+        if let Some(byte) = (0..=255u8).find(|b| !encoder.contains_key(&vec![*b])) {
+            anyhow::bail!("byte {} is missing in the vocabulary", byte);
+        }
+        let decoder = encoder.iter().map(|(k, v)| (*v, k.clone())).collect();
+        Ok(Self {
+            encoder,
+            decoder,
+            pattern: fancy_regex::Regex::new(pattern)?,
+        })
+    }
+
+    /// from_tiktoken_file loads a `.tiktoken` vocabulary, one `base64-token rank` pair per line
+    pub fn from_tiktoken_file(path: impl AsRef<Path>, pattern: &str) -> anyhow::Result<Self> {
+        let mut encoder = HashMap::new();
+        for line in std::fs::read_to_string(path)?.lines() {
+            let Some((token, rank)) = line.split_once(' ') else {
+                continue;
+            };
+            encoder.insert(STANDARD.decode(token)?, rank.trim().parse()?);
+        }
+        Self::new(encoder, pattern)
+    }
+
+    /// cl100k_base loads the vocabulary of gpt-3.5 and gpt-4 from `cl100k_base.tiktoken`
+    pub fn cl100k_base(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_tiktoken_file(path, CL100K_PATTERN)
+    }
+
+    /// o200k_base loads the vocabulary of gpt-4o from `o200k_base.tiktoken`
+    pub fn o200k_base(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_tiktoken_file(path, O200K_PATTERN)
+    }
+
+    /// byte_pair_encode merges the lowest ranked adjacent pair until nothing can be merged
+    fn byte_pair_encode(&self, piece: &[u8]) -> Vec<u32> {
+        if let Some(rank) = self.encoder.get(piece) {
+            return vec![*rank];
+        }
+        // boundaries of the parts, every byte is a part at first
+        let mut parts = (0..=piece.len()).collect::<Vec<_>>();
+        while let Some((_, i)) = (0..parts.len().saturating_sub(2))
+            .filter_map(|i| Some((*self.encoder.get(&piece[parts[i]..parts[i + 2]])?, i)))
+            .min()
+        {
+            parts.remove(i + 1);
+        }
+        parts
+            .windows(2)
+            .map(|w| self.encoder[&piece[w[0]..w[1]]])
+            .collect()
+    }
+}
+
+impl Tokenizer for BpeTokenizer {
+    fn encode(&self, text: &str) -> Vec<u32> {
+        let mut tokens = vec![];
+        let mut end = 0;
+        for piece in self.pattern.find_iter(text) {
+            match piece {
+                Ok(piece) => {
+                    tokens.extend(self.byte_pair_encode(piece.as_str().as_bytes()));
+                    end = piece.end();
+                }
+                // the regex engine hit its backtrack limit, the rest of the text is encoded
+                // without pre-tokenization so nothing is lost
+                Err(_) => {
+                    tokens.extend(self.byte_pair_encode(&text.as_bytes()[end..]));
+                    break;
+                }
+            }
+        }
+        tokens
+    }
+
+    /// unknown tokens are skipped, bytes that are not valid utf-8 are replaced
+    fn decode(&self, tokens: &[u32]) -> String {
+        let bytes = tokens
+            .iter()
+            .filter_map(|token| self.decoder.get(token))
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+/// CharTokenizer counts every char as a token, a rough estimate when no vocabulary is at hand
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CharTokenizer;
+
+impl Tokenizer for CharTokenizer {
+    fn encode(&self, text: &str) -> Vec<u32> {
+        text.chars().map(u32::from).collect()
+    }
+
+    fn decode(&self, tokens: &[u32]) -> String {
+        tokens.iter().filter_map(|t| char::from_u32(*t)).collect()
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        text.chars().count()
+    }
+}
+
+#[test]
+fn test_bpe_tokenizer() {
+    let mut vocab = (0..=255u8)
+        .map(|b| format!("{} {}", STANDARD.encode([b]), b))
+        .collect::<Vec<_>>();
+    for (rank, token) in ["he", "ll", "hell", "hello", " w", "or", " wor"].iter().enumerate() {
+        vocab.push(format!("{} {}", STANDARD.encode(token), 256 + rank));
+    }
+    let path = std::env::temp_dir().join(format!("limitchain_vocab_{}.tiktoken", std::process::id()));
+    std::fs::write(&path, vocab.join("\n")).unwrap();
+    let tokenizer = BpeTokenizer::cl100k_base(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let tokens = tokenizer.encode("hello world");
+    // "hello" is a single token, " world" is merged to " wor" + "l" + "d"
+    assert_eq!(tokens, vec![259, 262, b'l' as u32, b'd' as u32]);
+    assert_eq!(tokenizer.encode("hellhe"), vec![258, 256]);
+    assert_eq!(tokenizer.decode(&tokens), "hello world");
+    assert_eq!(tokenizer.decode(&tokenizer.encode("你好")), "你好");
+
+    // a regex giving up on backtracking does not drop the rest of the text
+    let tokenizer = BpeTokenizer {
+        pattern: fancy_regex::RegexBuilder::new(CL100K_PATTERN)
+            .backtrack_limit(1)
+            .build()
+            .unwrap(),
+        ..tokenizer
+    };
+    let text = "hello world      \n";
+    assert!(tokenizer.pattern.find_iter(text).any(|piece| piece.is_err()));
+    assert_eq!(tokenizer.decode(&tokenizer.encode(text)), text);
+}
+
+#[test]
+fn test_count_message_tokens() {
+    let messages = vec![
+        Message {
+            role: Role::System,
+            content: "be nice".into(),
+            ..Default::default()
+        },
+        Message {
+            role: Role::User,
+            content: "hi".into(),
+            ..Default::default()
+        },
+    ];
+    // (3 + 6 + 7) + (3 + 4 + 2) + 3
+    assert_eq!(CharTokenizer.count_message_tokens(&messages), 28);
+}
@@ -55,7 +55,7 @@ impl VectorDB for MilvusClient {
         .send()
         .await?;
 
-        println!("{:#?}", res.json().await?);
+        println!("{:#?}", res.json::<serde_json::Value>().await?);
 
         todo!("")
     }